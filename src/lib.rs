@@ -1,9 +1,10 @@
 use std::{
     net::{IpAddr, SocketAddr, AddrParseError},
-    io,
+    io, fmt,
+    str::FromStr,
     string::ToString,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use tokio_util::codec::Decoder;
 use bytes::{BytesMut, Buf};
 use tezos_encoding::{
@@ -19,16 +20,122 @@ pub enum Command {
     FilterLocalPort(u16),
     FilterRemoteAddr(SocketAddr),
     Disconnected(SocketAddr, [u8; 32]),
+    Stats,
+    /// all peers the node is connected to, the firewall forgets every other peer
+    Resync(Vec<(SocketAddr, [u8; 32])>),
+    /// the firewall never blocks the trusted network or peer
+    Allow(Trusted),
+    Disallow(Trusted),
+    /// never accept the identity, whatever ip it comes from, the reason is for the log
+    BlockPeer(PeerId, String),
+    UnblockPeer(PeerId),
+    /// the biggest talkers by traffic, the number of ips and of public keys to show
+    Top(u16),
+    /// only the networks allowed to services may connect to the port, like the rpc of the node
+    Protect(u16),
+    Unprotect(u16),
+    AllowService(Prefix),
+    DisallowService(Prefix),
 }
 
+/// Identity of the peer, either its public key, or the hash of the public key,
+/// the latter is the peer id `idt...` without base58check encoding
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PeerId {
+    PublicKey([u8; 32]),
+    Hash([u8; 16]),
+}
+
+/// Address or network, written as `ip` or `ip/length`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Prefix {
+    pub ip: IpAddr,
+    pub length: u8,
+}
+
+/// Network or public key which bypasses the checks of the firewall
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Trusted {
+    Network(Prefix),
+    Peer([u8; 32]),
+}
+
+impl FromStr for Prefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let ip = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(Error::AddrParse)?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let length = match parts.next() {
+            Some(length) => length
+                .parse::<u8>()
+                .ok()
+                .filter(|length| *length <= max)
+                .ok_or_else(|| Error::PrefixParse(s.to_string()))?,
+            None => max,
+        };
+        Ok(Prefix { ip, length })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.length)
+    }
+}
+
+/// public key in hex, or an address or network
+impl FromStr for Trusted {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut public_key = [0; 32];
+        match hex::decode_to_slice(s, &mut public_key) {
+            Ok(()) => Ok(Trusted::Peer(public_key)),
+            Err(_) => s.parse().map(Trusted::Network),
+        }
+    }
+}
+
+impl fmt::Display for Trusted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trusted::Network(prefix) => write!(f, "{}", prefix),
+            Trusted::Peer(public_key) => write!(f, "{}", hex::encode(public_key)),
+        }
+    }
+}
+
+/// Human readable answer to a query command, the firewall writes it into the same connection
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Report(pub String);
+
 #[derive(Debug)]
 pub enum Error {
     WrongTag(u8),
     AddrParse(AddrParseError),
+    PrefixParse(String),
     Io(io::Error),
     Deserialization(de::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WrongTag(tag) => write!(f, "wrong tag: {}", tag),
+            Error::AddrParse(e) => write!(f, "{}", e),
+            Error::PrefixParse(s) => write!(f, "invalid prefix: {}", s),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Deserialization(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::Io(e)
@@ -48,6 +155,35 @@ impl Command {
                 address,
                 public_key,
             }) => Command::Disconnected(address.parse().map_err(Error::AddrParse)?, public_key),
+            CommandInner::Stats(()) => Command::Stats,
+            CommandInner::Resync(peers) => Command::Resync(
+                peers
+                    .into_iter()
+                    .map(|Connected { address, public_key }| {
+                        address
+                            .parse()
+                            .map_err(Error::AddrParse)
+                            .map(|address| (address, public_key))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            CommandInner::AllowNetwork(s) => Command::Allow(Trusted::Network(s.parse()?)),
+            CommandInner::AllowPeer(public_key) => Command::Allow(Trusted::Peer(public_key)),
+            CommandInner::DisallowNetwork(s) => Command::Disallow(Trusted::Network(s.parse()?)),
+            CommandInner::DisallowPeer(public_key) => Command::Disallow(Trusted::Peer(public_key)),
+            CommandInner::BlockPeerByKey(BlockPeerByKey { public_key, reason }) => {
+                Command::BlockPeer(PeerId::PublicKey(public_key), reason)
+            },
+            CommandInner::BlockPeerByHash(BlockPeerByHash { hash, reason }) => {
+                Command::BlockPeer(PeerId::Hash(hash), reason)
+            },
+            CommandInner::UnblockPeerByKey(public_key) => Command::UnblockPeer(PeerId::PublicKey(public_key)),
+            CommandInner::UnblockPeerByHash(hash) => Command::UnblockPeer(PeerId::Hash(hash)),
+            CommandInner::Top(count) => Command::Top(count),
+            CommandInner::Protect(port) => Command::Protect(port),
+            CommandInner::Unprotect(port) => Command::Unprotect(port),
+            CommandInner::AllowService(s) => Command::AllowService(s.parse()?),
+            CommandInner::DisallowService(s) => Command::DisallowService(s.parse()?),
         })
     }
 
@@ -61,11 +197,56 @@ impl Command {
                 address: s.to_string(),
                 public_key: public_key.clone(),
             }),
+            Command::Stats => CommandInner::Stats(()),
+            Command::Resync(peers) => CommandInner::Resync(
+                peers
+                    .iter()
+                    .map(|(address, public_key)| Connected {
+                        address: address.to_string(),
+                        public_key: public_key.clone(),
+                    })
+                    .collect(),
+            ),
+            Command::Allow(Trusted::Network(prefix)) => CommandInner::AllowNetwork(prefix.to_string()),
+            Command::Allow(Trusted::Peer(public_key)) => CommandInner::AllowPeer(public_key.clone()),
+            Command::Disallow(Trusted::Network(prefix)) => {
+                CommandInner::DisallowNetwork(prefix.to_string())
+            },
+            Command::Disallow(Trusted::Peer(public_key)) => {
+                CommandInner::DisallowPeer(public_key.clone())
+            },
+            Command::BlockPeer(PeerId::PublicKey(public_key), reason) => {
+                CommandInner::BlockPeerByKey(BlockPeerByKey {
+                    public_key: public_key.clone(),
+                    reason: reason.clone(),
+                })
+            },
+            Command::BlockPeer(PeerId::Hash(hash), reason) => {
+                CommandInner::BlockPeerByHash(BlockPeerByHash {
+                    hash: hash.clone(),
+                    reason: reason.clone(),
+                })
+            },
+            Command::UnblockPeer(PeerId::PublicKey(public_key)) => {
+                CommandInner::UnblockPeerByKey(public_key.clone())
+            },
+            Command::UnblockPeer(PeerId::Hash(hash)) => CommandInner::UnblockPeerByHash(hash.clone()),
+            Command::Top(count) => CommandInner::Top(*count),
+            Command::Protect(port) => CommandInner::Protect(*port),
+            Command::Unprotect(port) => CommandInner::Unprotect(*port),
+            Command::AllowService(prefix) => CommandInner::AllowService(prefix.to_string()),
+            Command::DisallowService(prefix) => CommandInner::DisallowService(prefix.to_string()),
         };
         binary_writer::write(&inner, &CommandInner::encoding())
     }
 }
 
+impl Report {
+    pub fn as_bytes(&self) -> Result<Vec<u8>, ser::Error> {
+        binary_writer::write(&self.0, &Encoding::String)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 enum CommandInner {
     Block(String),
@@ -73,6 +254,33 @@ enum CommandInner {
     FilterLocalPort(u16),
     FilterRemoteAddr(String),
     Disconnected(Disconnected),
+    Stats(()),
+    Resync(Vec<Connected>),
+    AllowNetwork(String),
+    AllowPeer([u8; 32]),
+    DisallowNetwork(String),
+    DisallowPeer([u8; 32]),
+    BlockPeerByKey(BlockPeerByKey),
+    BlockPeerByHash(BlockPeerByHash),
+    UnblockPeerByKey([u8; 32]),
+    UnblockPeerByHash([u8; 16]),
+    Top(u16),
+    Protect(u16),
+    Unprotect(u16),
+    AllowService(String),
+    DisallowService(String),
+}
+
+#[derive(Deserialize, Serialize)]
+struct BlockPeerByKey {
+    public_key: [u8; 32],
+    reason: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct BlockPeerByHash {
+    hash: [u8; 16],
+    reason: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -81,6 +289,12 @@ struct Disconnected {
     public_key: [u8; 32],
 }
 
+#[derive(Deserialize, Serialize)]
+struct Connected {
+    address: String,
+    public_key: [u8; 32],
+}
+
 has_encoding!(CommandInner, COMMAND_ENCODING, {
     Encoding::Tags(
         std::mem::size_of::<u8>(),
@@ -97,10 +311,75 @@ has_encoding!(CommandInner, COMMAND_ENCODING, {
                     Field::new("public_key", Encoding::sized(32, Encoding::Bytes)),
                 ]),
             ),
+            Tag::new(0x06, "Stats", Encoding::Unit),
+            Tag::new(
+                0x07,
+                "Resync",
+                Encoding::dynamic(Encoding::list(Encoding::Obj(vec![
+                    Field::new("address", Encoding::String),
+                    Field::new("public_key", Encoding::sized(32, Encoding::Bytes)),
+                ]))),
+            ),
+            Tag::new(0x08, "AllowNetwork", Encoding::String),
+            Tag::new(0x09, "AllowPeer", Encoding::sized(32, Encoding::Bytes)),
+            Tag::new(0x0a, "DisallowNetwork", Encoding::String),
+            Tag::new(0x0b, "DisallowPeer", Encoding::sized(32, Encoding::Bytes)),
+            Tag::new(
+                0x0c,
+                "BlockPeerByKey",
+                Encoding::Obj(vec![
+                    Field::new("public_key", Encoding::sized(32, Encoding::Bytes)),
+                    Field::new("reason", Encoding::String),
+                ]),
+            ),
+            Tag::new(
+                0x0d,
+                "BlockPeerByHash",
+                Encoding::Obj(vec![
+                    Field::new("hash", Encoding::sized(16, Encoding::Bytes)),
+                    Field::new("reason", Encoding::String),
+                ]),
+            ),
+            Tag::new(0x0e, "UnblockPeerByKey", Encoding::sized(32, Encoding::Bytes)),
+            Tag::new(0x0f, "UnblockPeerByHash", Encoding::sized(16, Encoding::Bytes)),
+            Tag::new(0x10, "Top", Encoding::Uint16),
+            Tag::new(0x11, "Protect", Encoding::Uint16),
+            Tag::new(0x12, "Unprotect", Encoding::Uint16),
+            Tag::new(0x13, "AllowService", Encoding::String),
+            Tag::new(0x14, "DisallowService", Encoding::String),
         ]),
     )
 });
 
+fn decode<T>(src: &mut BytesMut, encoding: &Encoding) -> Result<Option<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let len = src.len();
+    match BinaryReader::new().read(&src, encoding) {
+        Ok(value) => {
+            src.advance(len);
+            de::from_value(&value)
+                .map_err(|e| match e {
+                    BinaryReaderError::DeserializationError { error } => {
+                        Error::Deserialization(error)
+                    },
+                    _ => unreachable!(),
+                })
+                .map(Some)
+        },
+        Err(BinaryReaderError::Overflow { bytes }) => {
+            let mut data = src.split_to(len - bytes);
+            decode(&mut data, encoding)
+        },
+        Err(BinaryReaderError::Underflow { .. }) => Ok(None),
+        Err(BinaryReaderError::DeserializationError { error }) => {
+            Err(Error::Deserialization(error))
+        },
+        Err(BinaryReaderError::UnsupportedTag { tag }) => Err(Error::WrongTag(tag as u8)),
+    }
+}
+
 pub struct CommandDecoder;
 
 impl Decoder for CommandDecoder {
@@ -108,33 +387,24 @@ impl Decoder for CommandDecoder {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let len = src.len();
-        match BinaryReader::new().read(&src, &CommandInner::encoding()) {
-            Ok(value) => {
-                src.advance(len);
-                de::from_value(&value)
-                    .map_err(|e| match e {
-                        BinaryReaderError::DeserializationError { error } => {
-                            Error::Deserialization(error)
-                        },
-                        _ => unreachable!(),
-                    })
-                    .and_then(Command::from_inner)
-                    .map(Some)
-            },
-            Err(BinaryReaderError::Overflow { bytes }) => {
-                let mut data = src.split_to(len - bytes);
-                self.decode(&mut data)
-            },
-            Err(BinaryReaderError::Underflow { .. }) => Ok(None),
-            Err(BinaryReaderError::DeserializationError { error }) => {
-                Err(Error::Deserialization(error))
-            },
-            Err(BinaryReaderError::UnsupportedTag { tag }) => Err(Error::WrongTag(tag as u8)),
+        match decode::<CommandInner>(src, &CommandInner::encoding())? {
+            Some(inner) => Command::from_inner(inner).map(Some),
+            None => Ok(None),
         }
     }
 }
 
+pub struct ReportDecoder;
+
+impl Decoder for ReportDecoder {
+    type Item = Report;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode::<String>(src, &Encoding::String).map(|s| s.map(Report))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -143,7 +413,7 @@ mod tests {
     };
     use bytes::BytesMut;
     use tokio_util::codec::Decoder;
-    use super::{CommandDecoder, Command};
+    use super::{CommandDecoder, Command, ReportDecoder, Report, Trusted, Prefix, PeerId};
 
     #[test]
     fn basic() {
@@ -201,4 +471,108 @@ mod tests {
         );
         assert_eq!(b.as_ref(), b"");
     }
+
+    #[test]
+    fn stats_and_report() {
+        let mut b = BytesMut::from(Command::Stats.as_bytes().unwrap().as_slice());
+        let c = CommandDecoder.decode(&mut b);
+        assert_eq!(c.unwrap().unwrap(), Command::Stats);
+        assert_eq!(b.as_ref(), b"");
+
+        let report = Report("proof of work cache: hits: 1".to_string());
+        let mut b = BytesMut::from(report.as_bytes().unwrap().as_slice());
+        let r = ReportDecoder.decode(&mut b);
+        assert_eq!(r.unwrap().unwrap(), report);
+        assert_eq!(b.as_ref(), b"");
+    }
+
+    #[test]
+    fn top() {
+        let mut data = Command::Top(10).as_bytes().unwrap();
+        data.extend_from_slice(&Command::Top(u16::MAX).as_bytes().unwrap());
+
+        let mut b = BytesMut::from(data.as_slice());
+        assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), Command::Top(10));
+        assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), Command::Top(u16::MAX));
+        assert_eq!(b.as_ref(), b"");
+    }
+
+    #[test]
+    fn resync() {
+        let peers = vec![
+            ("123.145.167.189:1234".parse().unwrap(), [1; 32]),
+            ("10.0.0.1:9732".parse().unwrap(), [2; 32]),
+        ];
+        let mut data = Command::Resync(peers.clone()).as_bytes().unwrap();
+        data.extend_from_slice(b"next");
+
+        let mut b = BytesMut::from(data.as_slice());
+        let c = CommandDecoder.decode(&mut b);
+        assert_eq!(c.unwrap().unwrap(), Command::Resync(peers));
+        assert_eq!(b.as_ref(), b"next");
+    }
+
+    #[test]
+    fn allow() {
+        let network = "10.0.0.0/8".parse::<Trusted>().unwrap();
+        assert_eq!(
+            network,
+            Trusted::Network(Prefix {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)),
+                length: 8,
+            })
+        );
+        let host = "10.0.0.1".parse::<Trusted>().unwrap();
+        assert_eq!(host, Trusted::Network("10.0.0.1/32".parse().unwrap()));
+        let peer = hex::encode([3; 32]).parse::<Trusted>().unwrap();
+        assert_eq!(peer, Trusted::Peer([3; 32]));
+        assert!("10.0.0.0/33".parse::<Trusted>().is_err());
+
+        let mut data = Command::Allow(network.clone()).as_bytes().unwrap();
+        data.extend_from_slice(&Command::Disallow(peer.clone()).as_bytes().unwrap());
+        let mut b = BytesMut::from(data.as_slice());
+        assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), Command::Allow(network));
+        assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), Command::Disallow(peer));
+        assert_eq!(b.as_ref(), b"");
+    }
+
+    #[test]
+    fn block_peer() {
+        let commands = vec![
+            Command::BlockPeer(PeerId::PublicKey([1; 32]), "bad block".to_string()),
+            Command::BlockPeer(PeerId::Hash([2; 16]), "bad operation".to_string()),
+            Command::UnblockPeer(PeerId::PublicKey([1; 32])),
+            Command::UnblockPeer(PeerId::Hash([2; 16])),
+        ];
+        let mut data = Vec::new();
+        for command in &commands {
+            data.extend_from_slice(&command.as_bytes().unwrap());
+        }
+
+        let mut b = BytesMut::from(data.as_slice());
+        for command in commands {
+            assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), command);
+        }
+        assert_eq!(b.as_ref(), b"");
+    }
+
+    #[test]
+    fn protect() {
+        let commands = vec![
+            Command::Protect(8732),
+            Command::AllowService("10.0.0.0/8".parse().unwrap()),
+            Command::DisallowService("192.168.1.1".parse().unwrap()),
+            Command::Unprotect(8732),
+        ];
+        let mut data = Vec::new();
+        for command in &commands {
+            data.extend_from_slice(&command.as_bytes().unwrap());
+        }
+
+        let mut b = BytesMut::from(data.as_slice());
+        for command in commands {
+            assert_eq!(CommandDecoder.decode(&mut b).unwrap().unwrap(), command);
+        }
+        assert_eq!(b.as_ref(), b"");
+    }
 }
@@ -2,8 +2,10 @@
 
 use std::net::IpAddr;
 use structopt::StructOpt;
-use tokio::{io::AsyncWriteExt, net::UnixStream};
-use tezedge_firewall_command::Command;
+use tokio::{io::AsyncWriteExt, net::UnixStream, stream::StreamExt};
+use tokio_util::codec::Framed;
+use tezedge_firewall_command::{Command, ReportDecoder, Trusted, PeerId, Prefix};
+use tezedge_firewall::peer_id;
 
 #[derive(StructOpt)]
 struct Opts {
@@ -18,6 +20,32 @@ enum Cmd {
     Block { addr: IpAddr },
     Unblock { addr: IpAddr },
     Node { port: u16 },
+    /// ip, network or public key in hex
+    Allow { trusted: Trusted },
+    Disallow { trusted: Trusted },
+    /// only the networks allowed to services may connect to the port
+    Protect { port: u16 },
+    Unprotect { port: u16 },
+    /// ip or network
+    ServiceAllow { prefix: Prefix },
+    ServiceDisallow { prefix: Prefix },
+    /// public key in hex or peer id
+    BlockPeer {
+        #[structopt(parse(try_from_str = peer_id::parse))]
+        peer: PeerId,
+        #[structopt(default_value = "blocked by operator")]
+        reason: String,
+    },
+    UnblockPeer {
+        #[structopt(parse(try_from_str = peer_id::parse))]
+        peer: PeerId,
+    },
+    Stats,
+    /// the biggest talkers of the last minute, hour and day
+    Top {
+        #[structopt(default_value = "10")]
+        count: u16,
+    },
 }
 
 #[tokio::main]
@@ -29,9 +57,26 @@ async fn main() {
         Cmd::Block { addr } => Command::Block(addr),
         Cmd::Unblock { addr } => Command::Unblock(addr),
         Cmd::Node { port } => Command::FilterLocalPort(port),
+        Cmd::Allow { trusted } => Command::Allow(trusted),
+        Cmd::Disallow { trusted } => Command::Disallow(trusted),
+        Cmd::Protect { port } => Command::Protect(port),
+        Cmd::Unprotect { port } => Command::Unprotect(port),
+        Cmd::ServiceAllow { prefix } => Command::AllowService(prefix),
+        Cmd::ServiceDisallow { prefix } => Command::DisallowService(prefix),
+        Cmd::BlockPeer { peer, reason } => Command::BlockPeer(peer, reason),
+        Cmd::UnblockPeer { peer } => Command::UnblockPeer(peer),
+        Cmd::Stats => Command::Stats,
+        Cmd::Top { count } => Command::Top(count),
     };
     control
         .write_all(command.as_bytes().unwrap().as_ref())
         .await
         .unwrap();
+
+    if let Command::Stats | Command::Top(_) = command {
+        let mut reports = Framed::new(control, ReportDecoder);
+        if let Some(report) = reports.next().await {
+            println!("{}", report.unwrap().0);
+        }
+    }
 }
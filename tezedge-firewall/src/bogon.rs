@@ -0,0 +1,77 @@
+use std::{ffi::CStr, io, ptr};
+
+use xdp_module::{Ipv4Prefix, Counter};
+
+/// Sources which never appear on the internet, private networks are not here,
+/// the node might have peers in the local network
+const BOGONS: [([u8; 4], u32, Counter); 10] = [
+    ([127, 0, 0, 0], 8, Counter::Loopback),
+    ([169, 254, 0, 0], 16, Counter::LinkLocal),
+    ([224, 0, 0, 0], 4, Counter::Multicast),
+    ([0, 0, 0, 0], 8, Counter::Reserved),
+    ([192, 0, 0, 0], 24, Counter::Reserved),
+    ([192, 0, 2, 0], 24, Counter::Reserved),
+    ([198, 18, 0, 0], 15, Counter::Reserved),
+    ([198, 51, 100, 0], 24, Counter::Reserved),
+    ([203, 0, 113, 0], 24, Counter::Reserved),
+    ([240, 0, 0, 0], 4, Counter::Reserved),
+];
+
+/// The built-in bogon networks and the counter of their category
+pub fn builtin() -> impl Iterator<Item = (Ipv4Prefix, Counter)> {
+    BOGONS
+        .iter()
+        .map(|(ipv4, prefix_length, counter)| (Ipv4Prefix::new(*ipv4, *prefix_length), *counter))
+}
+
+/// ipv4 addresses of the interface the firewall is attached to, the packet from outside
+/// with one of them as the source is spoofed, the addresses of the other interfaces
+/// might be legitimately routed through it
+pub fn host_addresses(device: &str) -> io::Result<Vec<[u8; 4]>> {
+    let mut addresses = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ipv4s = Vec::new();
+    let mut cursor = addresses;
+    while !cursor.is_null() {
+        let interface = unsafe { &*cursor };
+        let address = interface.ifa_addr;
+        let name = unsafe { CStr::from_ptr(interface.ifa_name) };
+        if name.to_bytes() == device.as_bytes()
+            && !address.is_null()
+            && unsafe { (*address).sa_family } as i32 == libc::AF_INET
+        {
+            let address = unsafe { &*(address as *const libc::sockaddr_in) };
+            // `s_addr` is in network byte order
+            ipv4s.push(address.sin_addr.s_addr.to_ne_bytes());
+        }
+        cursor = interface.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(addresses) };
+    Ok(ipv4s)
+}
+
+#[cfg(test)]
+mod tests {
+    use xdp_module::{Ipv4Prefix, Counter};
+    use super::{builtin, host_addresses};
+
+    #[test]
+    fn builtin_bogons() {
+        let bogons = builtin().collect::<Vec<_>>();
+        assert!(bogons
+            .iter()
+            .any(|(prefix, counter)| *prefix == Ipv4Prefix::new([127, 0, 0, 1], 8)
+                && *counter as u32 == Counter::Loopback as u32));
+        // the private networks are not bogons
+        assert!(!bogons.iter().any(|(prefix, _)| prefix.ipv4[0] == 10));
+    }
+
+    #[test]
+    fn addresses_of_the_device() {
+        assert!(host_addresses("lo").unwrap().contains(&[127, 0, 0, 1]));
+        assert!(host_addresses("no-such-device").unwrap().is_empty());
+    }
+}
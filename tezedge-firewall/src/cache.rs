@@ -0,0 +1,95 @@
+use std::fmt;
+use lru::LruCache;
+
+/// Remembers the result of proof of work check for the public key and the stamp,
+/// reconnecting peer sends the same data, no need to compute the hash again
+pub struct PowCache {
+    target: f64,
+    verdicts: LruCache<[u8; 56], bool>,
+    stats: PowCacheStats,
+}
+
+#[derive(Default, Clone)]
+pub struct PowCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl PowCache {
+    pub fn new(capacity: usize, target: f64) -> Self {
+        PowCache {
+            target,
+            verdicts: LruCache::new(capacity),
+            stats: PowCacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// verdict is only valid for the target it was computed with
+    fn ensure_target(&mut self, target: f64) {
+        if self.target != target {
+            self.target = target;
+            self.verdicts.clear();
+        }
+    }
+
+    pub fn get(&mut self, pow: &[u8; 56], target: f64) -> Option<bool> {
+        self.ensure_target(target);
+        let verdict = self.verdicts.get(pow).cloned();
+        match verdict {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        verdict
+    }
+
+    pub fn insert(&mut self, pow: [u8; 56], target: f64, valid: bool) {
+        self.ensure_target(target);
+        self.verdicts.put(pow, valid);
+    }
+
+    pub fn stats(&self) -> PowCacheStats {
+        PowCacheStats {
+            entries: self.verdicts.len(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+impl fmt::Display for PowCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "proof of work cache: hits: {}, misses: {}, entries: {}/{}",
+            self.hits, self.misses, self.entries, self.capacity
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PowCache;
+
+    #[test]
+    fn evict_and_target() {
+        let mut cache = PowCache::new(2, 26.0);
+        cache.insert([0; 56], 26.0, true);
+        cache.insert([1; 56], 26.0, false);
+        assert_eq!(cache.get(&[0; 56], 26.0), Some(true));
+        assert_eq!(cache.get(&[1; 56], 26.0), Some(false));
+
+        // the least recently used is evicted
+        cache.insert([2; 56], 26.0, true);
+        assert_eq!(cache.get(&[0; 56], 26.0), None);
+
+        // another target invalidates everything
+        assert_eq!(cache.get(&[1; 56], 24.0), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 0));
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use xdp_module::Ipv4Prefix;
+
+/// Counts distinct public keys every source tries in a sliding window,
+/// an attacker can generate many cheap identities and try them one after another
+pub struct ChurnTracker {
+    limit: usize,
+    window: Duration,
+    prefix_length: u32,
+    seen: HashMap<Ipv4Prefix, VecDeque<(Instant, [u8; 32])>>,
+    last_prune: Option<Instant>,
+}
+
+impl ChurnTracker {
+    /// the source is the ip if `prefix_length` is 32, or its network otherwise,
+    /// zero `limit` disables the tracker
+    pub fn new(limit: usize, window: Duration, prefix_length: u32) -> Self {
+        ChurnTracker {
+            limit,
+            window,
+            prefix_length,
+            seen: HashMap::new(),
+            last_prune: None,
+        }
+    }
+
+    /// remembers the public key, returns true if the source has tried too many identities
+    pub fn observe(&mut self, ipv4: [u8; 4], public_key: [u8; 32], now: Instant) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        self.prune(now);
+
+        let window = self.window;
+        let seen = self
+            .seen
+            .entry(Ipv4Prefix::new(ipv4, self.prefix_length))
+            .or_default();
+        while let Some(&(since, _)) = seen.front() {
+            if now.duration_since(since) > window {
+                seen.pop_front();
+            } else {
+                break;
+            }
+        }
+        if !seen.iter().any(|(_, seen_key)| seen_key.eq(&public_key)) {
+            seen.push_back((now, public_key));
+        }
+        seen.len() > self.limit
+    }
+
+    /// forget the sources which tried nothing during the window
+    fn prune(&mut self, now: Instant) {
+        let last_prune = *self.last_prune.get_or_insert(now);
+        if now.duration_since(last_prune) < self.window {
+            return;
+        }
+        self.last_prune = Some(now);
+        let window = self.window;
+        self.seen.retain(|_, seen| {
+            seen.back()
+                .map(|&(since, _)| now.duration_since(since) <= window)
+                .unwrap_or(false)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::ChurnTracker;
+
+    #[test]
+    fn limit_and_window() {
+        let now = Instant::now();
+        let mut churn = ChurnTracker::new(2, Duration::from_secs(60), 24);
+        assert!(!churn.observe([10, 0, 0, 1], [1; 32], now));
+        // the same identity again is not churn
+        assert!(!churn.observe([10, 0, 0, 1], [1; 32], now));
+        assert!(!churn.observe([10, 0, 0, 2], [2; 32], now));
+        // the third identity from the same network
+        assert!(churn.observe([10, 0, 0, 3], [3; 32], now));
+        // another network
+        assert!(!churn.observe([10, 0, 1, 1], [4; 32], now));
+
+        // the old identities leave the window
+        let later = now + Duration::from_secs(61);
+        assert!(!churn.observe([10, 0, 0, 1], [5; 32], later));
+        assert_eq!(churn.seen.len(), 1);
+    }
+}
@@ -1,25 +1,59 @@
+mod pow;
+mod cache;
+mod verifier;
+mod maps;
+mod liveness;
+mod scope;
+mod churn;
+mod traffic;
+mod bogon;
+pub mod peer_id;
+
 use std::{
-    env, fs, io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    env, fmt, fs, io,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
     os::unix::fs::PermissionsExt,
     path::Path,
     ptr,
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use redbpf::{
+    load::{Loader, Loaded},
+    xdp::Flags,
 };
-use redbpf::{load::Loader, xdp::Flags, HashMap, Module};
 use tokio::{
     signal,
     net::UnixListener,
+    task,
     stream::{StreamExt, Stream},
     sync::Mutex,
+    io::AsyncWriteExt,
+    time,
 };
 use tokio_util::codec::Framed;
 use slog::Drain;
 use structopt::StructOpt;
 
-use crypto::proof_of_work::check_proof_of_work;
-use xdp_module::{Event, EventInner, BlockingReason, Endpoint};
-use tezedge_firewall_command::{CommandDecoder, Command};
+use xdp_module::{
+    Event, EventInner, BlockingReason, BanFlags, Endpoint, EndpointPair, Config, Counter, Ipv4Prefix,
+    PACKET_RATE_MAX,
+    FLOW_BYTE_RATE_MAX, FLOW_BYTE_BURST_MIN, PORT_SCAN_MAX, ProtocolPolicy,
+};
+use tezedge_firewall_command::{CommandDecoder, Command, Report, Trusted, Prefix};
+
+use self::{
+    cache::PowCache,
+    verifier::{Verifier, Job, OverflowPolicy},
+    maps::{Maps, MapHandle},
+    scope::{BanPolicy, BanTraffic, ScopeRule},
+    churn::ChurnTracker,
+    liveness::Liveness,
+};
 
 #[derive(StructOpt)]
 pub struct Opts {
@@ -32,10 +66,202 @@ pub struct Opts {
     pub device: String,
     #[structopt(short, long, help = "Blacklist an IP, currently only ipv4 format supported")]
     pub blacklist: Vec<String>,
+    #[structopt(
+        long,
+        help = "Trust an IP, a network like 10.0.0.0/8, or a public key in hex, the firewall never blocks them"
+    )]
+    pub allow: Vec<Trusted>,
+    #[structopt(long, help = "Private mode, only trusted peers may connect to the node")]
+    pub private: bool,
     #[structopt(short, long, default_value = "26.0", help = "Configure required complexity of the proof of work")]
     pub target: f64,
     #[structopt(short, long, default_value = "/tmp/tezedge_firewall.sock", help = "Path where should create socket")]
     pub socket: String,
+    #[structopt(long, help = "Verify proof of work in the kernel, fall back to userspace if the kernel rejects the program")]
+    pub kernel_pow: bool,
+    #[structopt(long, default_value = "4096", help = "How many proof of work verdicts to remember")]
+    pub pow_cache: usize,
+    #[structopt(
+        long,
+        default_value = "4",
+        parse(try_from_str = positive),
+        help = "How many workers verify proof of work simultaneously"
+    )]
+    pub pow_workers: usize,
+    #[structopt(
+        long,
+        default_value = "1024",
+        parse(try_from_str = positive),
+        help = "How many proofs of work can wait for a free worker"
+    )]
+    pub pow_queue: usize,
+    #[structopt(
+        long,
+        default_value = "wait",
+        help = "What to do when the proof of work queue is full: wait, accept or block"
+    )]
+    pub pow_overflow: OverflowPolicy,
+    #[structopt(
+        long,
+        default_value = "60",
+        parse(try_from_str = positive),
+        help = "How often to remove stale map entries, in seconds"
+    )]
+    pub sweep_interval: u64,
+    #[structopt(
+        long,
+        default_value = "600",
+        help = "Remove entries not related to any live connection after this many seconds"
+    )]
+    pub entry_ttl: u64,
+    #[structopt(
+        long,
+        help = "What to ban for the reason: reason=flow, reason=ip or reason=prefix/<length>, the default is ip, but flow for handshake-timeout"
+    )]
+    pub ban_scope: Vec<ScopeRule>,
+    #[structopt(
+        long,
+        default_value = "all",
+        help = "Which traffic of the banned source to drop: node or all, the ips given by --blacklist are banned for all traffic"
+    )]
+    pub ban_traffic: BanTraffic,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Block the source which tries more identities than this during the churn window, 0 disables it"
+    )]
+    pub churn_limit: usize,
+    #[structopt(long, default_value = "60", help = "The window to count identities of the source, in seconds")]
+    pub churn_window: u64,
+    #[structopt(
+        long,
+        default_value = "32",
+        help = "Count identities of the whole network of this prefix length, 32 counts every ip separately"
+    )]
+    pub churn_prefix: u32,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "New connections per second to the node from a single source, 0 disables the limit"
+    )]
+    pub syn_rate: u64,
+    #[structopt(long, default_value = "20", help = "How many new connections the source can open at once")]
+    pub syn_burst: u64,
+    #[structopt(
+        long,
+        default_value = "32",
+        help = "Limit new connections of the whole network of this prefix length, 32 limits every ip separately"
+    )]
+    pub syn_prefix: u32,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "How many live connections to the node a single ip can hold, 0 disables the limit"
+    )]
+    pub max_connections_per_ip: u32,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "How many handshakes can wait for proof of work verification, 0 disables the limit"
+    )]
+    pub max_handshakes: u64,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Kick the connection to the node which sends nothing during this many seconds, 0 disables it"
+    )]
+    pub handshake_deadline: u64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Bytes per second a verified peer can send, 0 disables the quota"
+    )]
+    pub flow_byte_rate: u64,
+    #[structopt(long, default_value = "4194304", help = "How many bytes a verified peer can send at once")]
+    pub flow_byte_burst: u64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Packets per second a verified peer can send, 0 disables the quota"
+    )]
+    pub flow_packet_rate: u64,
+    #[structopt(long, default_value = "1000", help = "How many packets a verified peer can send at once")]
+    pub flow_packet_burst: u64,
+    #[structopt(
+        long,
+        default_value = "100",
+        help = "Block the peer after this many packets over the quota are dropped, 0 only drops them"
+    )]
+    pub quota_strikes: u32,
+    #[structopt(
+        long,
+        default_value = "0",
+        parse(try_from_str = scan_threshold),
+        help = "Report the source which tries this many distinct ports of the host during the scan window, at least 2, 0 disables it"
+    )]
+    pub scan_ports: u32,
+    #[structopt(
+        long,
+        default_value = "10",
+        parse(try_from_str = positive),
+        help = "The window to count ports the source tries, in seconds"
+    )]
+    pub scan_window: u64,
+    #[structopt(long, help = "Block the source which scans ports, otherwise it is only reported")]
+    pub scan_ban: bool,
+    #[structopt(
+        long,
+        conflicts_with = "no-bogons",
+        help = "Drop the traffic to the node from the network, in addition to the built-in bogons"
+    )]
+    pub bogon: Vec<Prefix>,
+    #[structopt(long, help = "Accept bogon sources and the addresses of the interface from outside")]
+    pub no_bogons: bool,
+    #[structopt(long, help = "Protect the port of the host, like the rpc of the node, only allowed sources may connect")]
+    pub protect: Vec<u16>,
+    #[structopt(long, help = "Allow an IP or a network like 10.0.0.0/8 to connect to the protected ports")]
+    pub service_allow: Vec<Prefix>,
+    #[structopt(long, default_value = "pass", help = "What to do with udp: pass, drop or rate-limit")]
+    pub udp_policy: ProtocolPolicy,
+    #[structopt(long, default_value = "1000", help = "Udp packets per second from a single ip, if rate limited")]
+    pub udp_rate: u64,
+    #[structopt(long, default_value = "1000", help = "How many udp packets a single ip can send at once")]
+    pub udp_burst: u64,
+    #[structopt(long, default_value = "pass", help = "What to do with icmp: pass, drop or rate-limit")]
+    pub icmp_policy: ProtocolPolicy,
+    #[structopt(long, default_value = "10", help = "Icmp packets per second from a single ip, if rate limited")]
+    pub icmp_rate: u64,
+    #[structopt(long, default_value = "20", help = "How many icmp packets a single ip can send at once")]
+    pub icmp_burst: u64,
+    #[structopt(long, help = "Drop every protocol of the banned source, not only tcp")]
+    pub ban_all_protocols: bool,
+    #[structopt(
+        long,
+        help = "Open ip-in-ip and vxlan tunnels from the endpoint, an IP or a network, and check the traffic inside"
+    )]
+    pub tunnel: Vec<Prefix>,
+}
+
+/// the argument which makes no sense if it is zero, like the size of the queue
+fn positive<T>(s: &str) -> Result<T, String>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: fmt::Display,
+{
+    match s.parse::<T>() {
+        Ok(value) if value == T::default() => Err("must be greater than zero".to_string()),
+        Ok(value) => Ok(value),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// a single port is not a scan, every source connecting to the node would be reported
+fn scan_threshold(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(1) => Err("must be at least 2, or 0 to disable it".to_string()),
+        Ok(value) => Ok(value),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 pub fn logger() -> slog::Logger {
@@ -50,11 +276,41 @@ pub fn logger() -> slog::Logger {
     slog::Logger::root(drain, slog::o!())
 }
 
-async fn event_handler<E>(events: E, module: Arc<Mutex<Module>>, target: f64, log: &slog::Logger)
-where
+/// ban the source for the reason of the event, or for trying too many identities
+async fn conclude(
+    maps: &mut MapHandle,
+    pair: EndpointPair,
+    reason: Option<BlockingReason>,
+    churning: bool,
+    log: &slog::Logger,
+) {
+    let reason = match reason {
+        None if churning => {
+            slog::info!(log, "Too many identities from {:?}", pair.remote);
+            Some(BlockingReason::IdentityChurn)
+        },
+        reason => reason,
+    };
+    if let Some(reason) = reason {
+        maps.ban(pair, reason).await;
+    }
+}
+
+async fn event_handler<E>(
+    events: E,
+    maps: MapHandle,
+    verifier: Verifier,
+    churn: ChurnTracker,
+    ban_scans: bool,
+    log: &slog::Logger,
+) where
     E: Unpin + Send + Stream<Item = (String, Vec<Box<[u8]>>)> + 'static,
 {
     let mut events = events;
+    let mut maps = maps;
+    let mut verifier = verifier;
+    let mut churn = churn;
+    let liveness = Arc::new(Liveness::new(Duration::from_secs(1)));
     while let Some((name, events)) = events.next().await {
         for event in events {
             match name.as_str() {
@@ -62,58 +318,111 @@ where
                     // TODO: remove unsafe
                     let event = unsafe { ptr::read(event.as_ptr() as *const Event) };
 
-                    let module = module.lock().await;
-                    with_map_ref(&module, "blacklist", |map| {
-                        let ip = event.pair.remote.ipv4;
-                        match &event.event {
-                            EventInner::ReceivedPow(b) => {
-                                slog::info!(
-                                    log,
-                                    "Received proof of work: {}",
-                                    hex::encode(b.as_ref())
-                                );
-                                match check_proof_of_work(b, target) {
-                                    Ok(()) => slog::info!(
-                                        log,
-                                        "Proof of work is valid, complexity: {}",
-                                        target
-                                    ),
-                                    Err(()) => block_ip(
-                                        &map,
-                                        IpAddr::V4(Ipv4Addr::from(ip)),
-                                        BlockingReason::BadProofOfWork,
-                                        log,
-                                    ),
-                                }
-                            },
-                            EventInner::NotEnoughBytesForPow => {
-                                slog::info!(log, "Received proof of work too short");
-                                block_ip(
-                                    &map,
-                                    IpAddr::V4(Ipv4Addr::from(ip)),
-                                    BlockingReason::BadProofOfWork,
-                                    log,
-                                )
-                            },
-                            EventInner::BlockedAlreadyConnected {
+                    let public_key = event.event.public_key();
+                    let churning = public_key
+                        .map(|public_key| churn.observe(event.pair.remote.ipv4, public_key, Instant::now()))
+                        .unwrap_or(false);
+                    // the identity might be blocked by its peer id
+                    if let Some(public_key) = public_key {
+                        maps.peer_seen(public_key).await;
+                    }
+
+                    let reason = match event.event {
+                        EventInner::ReceivedPow(b) => {
+                            slog::info!(log, "Received proof of work: {}", hex::encode(b.as_ref()));
+                            verifier.submit(Job { pair: event.pair.clone(), pow: b }).await;
+                            None
+                        },
+                        EventInner::BadProofOfWork(b) => {
+                            slog::info!(
+                                log,
+                                "Proof of work is invalid, checked in kernel: {}",
+                                hex::encode(b.as_ref())
+                            );
+                            Some(BlockingReason::BadProofOfWork)
+                        },
+                        EventInner::NotTrusted(public_key) => {
+                            // the connection is dropped, but the ip may have trusted peers
+                            slog::info!(log, "Rejected untrusted peer: {}", hex::encode(public_key.as_ref()));
+                            None
+                        },
+                        EventInner::DeniedPeer(public_key) => {
+                            // the identity is banned, not the ip
+                            slog::info!(log, "Rejected blocked peer: {}", hex::encode(public_key.as_ref()));
+                            None
+                        },
+                        EventInner::QuotaExceeded(dropped) => {
+                            slog::info!(log, "Peer {:?} exceeds its quota, dropped {} packets", event.pair.remote, dropped);
+                            Some(BlockingReason::QuotaExceeded)
+                        },
+                        EventInner::PortScan(ports) => {
+                            slog::info!(log, "Port scan from {:?}, tried {} ports", event.pair.remote, ports);
+                            if ban_scans {
+                                Some(BlockingReason::PortScan)
+                            } else {
+                                None
+                            }
+                        },
+                        EventInner::NotEnoughBytesForPow => {
+                            slog::info!(log, "Received proof of work too short");
+                            Some(BlockingReason::BadProofOfWork)
+                        },
+                        EventInner::NotTezosTraffic {
+                            chunk_length,
+                            payload_length,
+                        } => {
+                            slog::info!(
+                                log,
+                                "Received not tezos traffic, chunk length: {}, payload length: {}",
+                                chunk_length,
+                                payload_length
+                            );
+                            Some(BlockingReason::NotTezosTraffic)
+                        },
+                        EventInner::BlockedAlreadyConnected {
+                            already_connected,
+                            try_connect,
+                            public_key,
+                        } => {
+                            slog::info!(
+                                log,
+                                "Already connected: {:?}, try connect: {:?}",
                                 already_connected,
-                                try_connect,
-                            } => {
-                                slog::info!(
-                                    log,
-                                    "Already connected: {:?}, try connect: {:?}",
-                                    already_connected,
-                                    try_connect
-                                );
-                                block_ip(
-                                    &map,
-                                    IpAddr::V4(Ipv4Addr::from(ip)),
-                                    BlockingReason::AlreadyConnected,
-                                    log,
-                                )
-                            },
-                        }
-                    });
+                                try_connect
+                            );
+                            // reading the socket table is slow, the events behind should not wait for it
+                            let liveness = liveness.clone();
+                            let mut maps = maps.clone();
+                            let pair = event.pair.clone();
+                            let log = log.clone();
+                            tokio::spawn(async move {
+                                let old = already_connected.clone();
+                                let reason = match task::spawn_blocking(move || liveness.is_alive(&old)).await {
+                                    Ok(Ok(false)) => {
+                                        slog::info!(log, "Connection {:?} is dead, replace it", already_connected);
+                                        let new_pair = EndpointPair {
+                                            local: pair.local.clone(),
+                                            remote: try_connect,
+                                        };
+                                        maps.replace_peer(public_key, new_pair).await;
+                                        None
+                                    },
+                                    Ok(Ok(true)) => Some(BlockingReason::AlreadyConnected),
+                                    Ok(Err(e)) => {
+                                        slog::warn!(log, "Cannot read the socket table: {:?}", e);
+                                        Some(BlockingReason::AlreadyConnected)
+                                    },
+                                    Err(e) => {
+                                        slog::warn!(log, "Cannot read the socket table: {:?}", e);
+                                        Some(BlockingReason::AlreadyConnected)
+                                    },
+                                };
+                                conclude(&mut maps, pair, reason, churning, &log).await
+                            });
+                            continue;
+                        },
+                    };
+                    conclude(&mut maps, event.pair, reason, churning, log).await
                 },
                 unknown => slog::warn!(log, "Warning: ignored unknown event: {}", unknown),
             }
@@ -121,38 +430,27 @@ where
     }
 }
 
-fn block_ip<'a>(
-    map: &HashMap<'a, [u8; 4], u32>,
-    ip: IpAddr,
-    reason: BlockingReason,
-    log: &slog::Logger,
-) {
-    // TODO: store reason somewhere in userspace
-    slog::info!(log, "Block {}, reason: {:?}", ip, reason);
-    match ip {
-        IpAddr::V4(ip) => map.set(ip.octets(), 0),
-        IpAddr::V6(_) => unimplemented!(),
-    }
-}
-
-fn unblock_ip<'a>(map: HashMap<'a, [u8; 4], u32>, ip: IpAddr) {
-    match ip {
-        IpAddr::V4(ip) => map.delete(ip.octets()),
-        IpAddr::V6(_) => unimplemented!(),
+fn endpoint(address: &SocketAddrV4) -> Endpoint {
+    Endpoint {
+        ipv4: address.ip().octets(),
+        port: address.port().to_be_bytes(),
     }
 }
 
-fn with_map_ref<'a, 'b, F, K, V>(module: &'a Module, name: &'b str, f: F)
+/// the answer to the query command
+async fn send_report<W>(stream: &mut W, report: String, log: &slog::Logger)
 where
-    F: FnOnce(HashMap<'a, K, V>),
-    K: Clone,
-    V: Clone,
+    W: AsyncWriteExt + Unpin,
 {
-    if let Some(base) = module.maps.iter().find(|m| m.name == name) {
-        let map = HashMap::new(base).unwrap();
-        f(map)
-    } else {
-        panic!("{} not found", name)
+    let write = match Report(report).as_bytes() {
+        Ok(bytes) => stream.write_all(&bytes).await,
+        Err(e) => {
+            slog::error!(log, "Failed to serialize report: \"{:?}\"", e);
+            return;
+        },
+    };
+    if let Err(e) = write {
+        slog::error!(log, "Failed to send report: \"{}\"", e);
     }
 }
 
@@ -187,42 +485,202 @@ fn ensure_socket_permissions(socket_path: &Path, log: &slog::Logger) -> Result<(
     Ok(())
 }
 
-pub async fn firewall(opts: Opts, log: slog::Logger) {
-    let Opts {
-        device,
-        blacklist,
-        target,
-        socket,
-    } = opts;
+fn load(kernel_pow: bool, log: &slog::Logger) -> Loaded {
+    if kernel_pow {
+        let code = include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/target/bpf/programs/xdp_module_pow/xdp_module_pow.elf"
+        ));
+        match Loader::load(code) {
+            Ok(loaded) => {
+                slog::info!(log, "Proof of work is verified in kernel");
+                return loaded;
+            },
+            Err(e) => slog::warn!(
+                log,
+                "Cannot load BPF program verifying proof of work, fall back to userspace verification";
+                "reason" => format!("{:?}", e)
+            ),
+        }
+    }
 
     let code = include_bytes!(concat!(
         env!("OUT_DIR"),
         "/target/bpf/programs/xdp_module/xdp_module.elf"
     ));
-    let mut loaded = Loader::load(code)
+    Loader::load(code)
         .unwrap_or_else(|e| {
             slog::error!(log, "Cannot load BPF program, the docker container need to be privileged");
             panic!("{:?}", e);
-        });
+        })
+}
+
+pub async fn firewall(opts: Opts, log: slog::Logger) {
+    let Opts {
+        device,
+        blacklist,
+        allow,
+        private,
+        target,
+        socket,
+        kernel_pow,
+        pow_cache,
+        pow_workers,
+        pow_queue,
+        pow_overflow,
+        sweep_interval,
+        entry_ttl,
+        ban_scope,
+        ban_traffic,
+        churn_limit,
+        churn_window,
+        churn_prefix,
+        syn_rate,
+        syn_burst,
+        syn_prefix,
+        max_connections_per_ip,
+        max_handshakes,
+        handshake_deadline,
+        flow_byte_rate,
+        flow_byte_burst,
+        flow_packet_rate,
+        flow_packet_burst,
+        quota_strikes,
+        scan_ports,
+        scan_window,
+        scan_ban,
+        bogon,
+        no_bogons,
+        protect,
+        service_allow,
+        tunnel,
+        udp_policy,
+        udp_rate,
+        udp_burst,
+        icmp_policy,
+        icmp_rate,
+        icmp_burst,
+        ban_all_protocols,
+    } = opts;
+
+    let mut loaded = load(kernel_pow, &log);
+
+    // validate the maps and write the config before attaching, the program reads it
+    match Maps::new(&loaded.module) {
+        Ok(maps) => {
+            maps.set_config(Config {
+                pow_target: pow::make_target(target),
+                private_mode: private,
+                syn_rate: syn_rate.min(PACKET_RATE_MAX),
+                syn_burst: syn_burst.min(PACKET_RATE_MAX).max(1),
+                syn_prefix_length: syn_prefix.min(32),
+                max_connections_per_ip,
+                max_handshakes,
+                flow_byte_rate: flow_byte_rate.min(FLOW_BYTE_RATE_MAX),
+                flow_byte_burst: flow_byte_burst.min(FLOW_BYTE_RATE_MAX).max(FLOW_BYTE_BURST_MIN),
+                flow_packet_rate: flow_packet_rate.min(PACKET_RATE_MAX),
+                flow_packet_burst: flow_packet_burst.min(PACKET_RATE_MAX).max(1),
+                quota_strikes,
+                scan_ports: scan_ports.min(PORT_SCAN_MAX),
+                scan_window: Duration::from_secs(scan_window).as_nanos() as u64,
+                udp_policy,
+                udp_rate: udp_rate.min(PACKET_RATE_MAX),
+                udp_burst: udp_burst.min(PACKET_RATE_MAX).max(1),
+                icmp_policy,
+                icmp_rate: icmp_rate.min(PACKET_RATE_MAX),
+                icmp_burst: icmp_burst.min(PACKET_RATE_MAX).max(1),
+                ban_all_protocols,
+            });
+            maps.reset_counters();
+            if !no_bogons {
+                for (prefix, category) in bogon::builtin() {
+                    maps.add_bogon(prefix, category);
+                }
+                for prefix in bogon {
+                    match prefix.ip {
+                        IpAddr::V4(ip) => {
+                            let prefix = Ipv4Prefix::new(ip.octets(), prefix.length as u32);
+                            maps.add_bogon(prefix, Counter::Reserved)
+                        },
+                        IpAddr::V6(_) => slog::warn!(log, "Ignored ipv6 bogon: {}", prefix),
+                    }
+                }
+                match bogon::host_addresses(&device) {
+                    Ok(addresses) => maps.set_own_addresses(addresses, &log),
+                    Err(e) => slog::warn!(log, "Cannot read the addresses of {}: {}", device, e),
+                }
+            }
+            for trusted in allow {
+                maps.allow(trusted, &log);
+            }
+            for prefix in service_allow {
+                maps.allow_service(prefix, &log);
+            }
+            for prefix in tunnel {
+                maps.add_tunnel(prefix, &log);
+            }
+            for port in protect {
+                maps.protect(port, &log);
+            }
+            for block in blacklist {
+                let ip = block.parse::<IpAddr>().unwrap();
+                maps.block(ip, BlockingReason::CommandLineArgument, BanFlags::empty(), &log);
+            }
+        },
+        Err(e) => {
+            slog::error!(log, "The BPF program is broken"; "reason" => format!("{}", e));
+            panic!("{}", e);
+        },
+    }
+
     for kp in loaded.xdps_mut() {
         kp.attach_xdp(device.as_str(), Flags::Unset)
             .expect(&format!("Error attaching xdp program {}", kp.name()));
         slog::debug!(log, "Loaded xdp program: \"{}\"", kp.name());
     }
 
-    with_map_ref(&loaded.module, "blacklist", |map| {
-        for block in blacklist {
-            let ip = block.parse::<IpAddr>().unwrap();
-            block_ip(&map, ip, BlockingReason::CommandLineArgument, &log);
-        }
-    });
-
-    let module = Arc::new(Mutex::new(loaded.module));
+    let maps = MapHandle::spawn(
+        loaded.module,
+        BanPolicy::new(ban_scope, ban_traffic),
+        Duration::from_secs(sweep_interval),
+        Duration::from_secs(entry_ttl),
+        Duration::from_secs(handshake_deadline),
+        &log,
+    );
+    let cache = Arc::new(Mutex::new(PowCache::new(pow_cache, target)));
+    let verifier = Verifier::spawn(
+        pow_workers,
+        pow_queue,
+        pow_overflow,
+        target,
+        maps.clone(),
+        cache.clone(),
+        &log,
+    );
+    if !no_bogons {
+        // the addresses of the interface might change
+        let mut maps = maps.clone();
+        let log = log.clone();
+        let device = device.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs(sweep_interval);
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match bogon::host_addresses(&device) {
+                    Ok(addresses) => maps.own_addresses(addresses).await,
+                    Err(e) => slog::warn!(log, "Cannot read the addresses of {}: {}", device, e),
+                }
+            }
+        });
+    }
+    let verifier_stats = verifier.stats();
+    let churn = ChurnTracker::new(churn_limit, Duration::from_secs(churn_window), churn_prefix);
     let events = loaded.events;
     {
-        let module = module.clone();
+        let maps = maps.clone();
         let log = log.clone();
-        tokio::spawn(async move { event_handler(events, module, target, &log).await });
+        tokio::spawn(async move { event_handler(events, maps, verifier, churn, scan_ban, &log).await });
     }
 
     tokio::spawn(async move {
@@ -249,15 +707,22 @@ pub async fn firewall(opts: Opts, log: slog::Logger) {
         }
 
         slog::info!(log, "Listening commands on unix domain socket"; "socket_path" => socket_path.as_os_str().to_str().unwrap());
+        // the session of the node is closed, the node is restarted if it registers the port again
+        let node_left = Arc::new(AtomicBool::new(false));
         loop {
             let (stream, _) = listener.accept().await.unwrap();
 
-            let module = module.clone();
+            let mut maps = maps.clone();
+            let cache = cache.clone();
+            let verifier_stats = verifier_stats.clone();
+            let node_left = node_left.clone();
             let log = log.clone();
             tokio::spawn(async move {
                 let mut command_stream = Framed::new(stream, CommandDecoder);
+                // the node keeps its session open and reports its connections,
+                // `fw` sends a single command and leaves
+                let mut node_session = false;
                 while let Some(command) = command_stream.next().await {
-                    let module = module.lock().await;
                     // if command is bad, ignore it and report error
                     let command = match command {
                         Ok(c) => c,
@@ -267,33 +732,64 @@ pub async fn firewall(opts: Opts, log: slog::Logger) {
                         },
                     };
                     slog::info!(log, "Received command: \"{:?}\"", command);
+                    node_session |= matches!(
+                        command,
+                        Command::FilterRemoteAddr(_) | Command::Disconnected(..) | Command::Resync(_)
+                    );
                     match command {
-                        Command::Block(ip) => with_map_ref(&module, "blacklist", |map| {
-                            block_ip(&map, ip, BlockingReason::EventFromTezedge, &log)
-                        }),
-                        Command::Unblock(ip) => {
-                            with_map_ref(&module, "blacklist", |map| unblock_ip(map, ip))
-                        },
+                        Command::Block(ip) => maps.block(ip, BlockingReason::EventFromTezedge).await,
+                        Command::Unblock(ip) => maps.unblock(ip).await,
                         Command::FilterLocalPort(port) => {
-                            with_map_ref::<_, u16, u32>(&module, "node", |map| map.set(port, 0))
+                            if node_left.swap(false, Ordering::Relaxed) {
+                                maps.forget_peers().await;
+                            }
+                            maps.set_port(port).await
                         },
                         Command::FilterRemoteAddr(SocketAddr::V4(a)) => {
-                            with_map_ref::<_, Endpoint, u32>(&module, "pending_peers", |map| {
-                                let endpoint = Endpoint {
-                                    ipv4: a.ip().octets(),
-                                    port: a.port().to_be_bytes(),
-                                };
-                                map.set(endpoint, 0)
-                            })
+                            maps.add_pending(endpoint(&a)).await
                         },
-                        Command::Disconnected(SocketAddr::V4(_), pk) => {
-                            with_map_ref::<_, [u8; 32], Endpoint>(&module, "peers", |map| {
-                                map.delete(pk)
-                            })
+                        Command::Disconnected(SocketAddr::V4(a), pk) => {
+                            maps.disconnected(endpoint(&a), pk).await
+                        },
+                        Command::BlockPeer(peer, reason) => maps.block_peer(peer, reason).await,
+                        Command::UnblockPeer(peer) => maps.unblock_peer(peer).await,
+                        Command::Allow(trusted) => maps.allow(trusted).await,
+                        Command::Disallow(trusted) => maps.disallow(trusted).await,
+                        Command::Protect(port) => maps.protect(port).await,
+                        Command::Unprotect(port) => maps.unprotect(port).await,
+                        Command::AllowService(prefix) => maps.allow_service(prefix).await,
+                        Command::DisallowService(prefix) => maps.disallow_service(prefix).await,
+                        Command::Resync(peers) => {
+                            let connected = peers
+                                .into_iter()
+                                .filter_map(|(address, pk)| match address {
+                                    SocketAddr::V4(a) => Some((endpoint(&a), pk)),
+                                    SocketAddr::V6(_) => None,
+                                })
+                                .collect();
+                            maps.resync(connected).await
+                        },
+                        Command::Stats => {
+                            let mut report = format!("{}\n{}", cache.lock().await.stats(), verifier_stats);
+                            if let Some(summary) = maps.summary().await {
+                                report = format!("{}\n{}", report, summary);
+                            }
+                            send_report(command_stream.get_mut(), report, &log).await
+                        },
+                        Command::Top(count) => {
+                            let report = match maps.top(count as usize).await {
+                                Some(top) => top.to_string(),
+                                None => "traffic is unknown".to_string(),
+                            };
+                            send_report(command_stream.get_mut(), report, &log).await
                         },
                         _ => slog::error!(log, "Not implemented yet"),
                     }
                 }
+                if node_session {
+                    slog::info!(log, "The node has closed its session");
+                    node_left.store(true, Ordering::Relaxed);
+                }
             });
         }
     });
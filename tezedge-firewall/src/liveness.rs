@@ -0,0 +1,143 @@
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    io, mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    ptr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use procfs::{ProcError, net::TcpState};
+
+use xdp_module::{Endpoint, EndpointPair};
+
+/// Remote endpoints of the established connections, the socket table is read at most once
+/// per `max_age`, so a flood of duplicate identities does not read it for every one
+pub struct Liveness {
+    max_age: Duration,
+    established: Mutex<Option<(Instant, HashSet<SocketAddr>)>>,
+}
+
+impl Liveness {
+    pub fn new(max_age: Duration) -> Self {
+        Liveness {
+            max_age,
+            established: Mutex::new(None),
+        }
+    }
+
+    /// Whether some local socket still has an established connection with the endpoint,
+    /// reads the socket table of the network namespace the firewall runs in,
+    /// so the node must share the namespace with the firewall, blocks while reading
+    pub fn is_alive(&self, endpoint: &Endpoint) -> Result<bool, ProcError> {
+        let remote = SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::from(endpoint.ipv4),
+            u16::from_be_bytes(endpoint.port),
+        ));
+        let mut established = self.established.lock().unwrap();
+        let fresh = matches!(&*established, Some((since, _)) if since.elapsed() < self.max_age);
+        if !fresh {
+            let remotes = procfs::net::tcp()?
+                .into_iter()
+                .filter(|entry| entry.state == TcpState::Established)
+                .map(|entry| entry.remote_address)
+                .collect();
+            *established = Some((Instant::now(), remotes));
+        }
+        Ok(established
+            .as_ref()
+            .map(|(_, remotes)| remotes.contains(&remote))
+            .unwrap_or(false))
+    }
+}
+
+/// `SOCK_DESTROY` of `linux/sock_diag.h`
+const SOCK_DESTROY: u16 = 21;
+const INET_DIAG_NOCOOKIE: u32 = !0;
+
+/// `struct inet_diag_sockid`, the ports and the addresses are in network byte order
+#[repr(C)]
+struct SocketId {
+    source_port: [u8; 2],
+    destination_port: [u8; 2],
+    source: [u8; 16],
+    destination: [u8; 16],
+    interface: u32,
+    cookie: [u32; 2],
+}
+
+/// `struct inet_diag_req_v2` behind the netlink header
+#[repr(C)]
+struct DestroyRequest {
+    header: libc::nlmsghdr,
+    family: u8,
+    protocol: u8,
+    extensions: u8,
+    pad: u8,
+    states: u32,
+    id: SocketId,
+}
+
+/// Closes the local socket of the connection, like `ss -K` does, the node sees the connection
+/// aborted, the kernel must be built with `CONFIG_INET_DIAG_DESTROY`
+pub fn destroy(pair: &EndpointPair) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_SOCK_DIAG) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = request_destroy(fd, pair);
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn request_destroy(fd: libc::c_int, pair: &EndpointPair) -> io::Result<()> {
+    let mut source = [0; 16];
+    source[..4].clone_from_slice(&pair.local.ipv4);
+    let mut destination = [0; 16];
+    destination[..4].clone_from_slice(&pair.remote.ipv4);
+    let request = DestroyRequest {
+        header: libc::nlmsghdr {
+            nlmsg_len: mem::size_of::<DestroyRequest>() as u32,
+            nlmsg_type: SOCK_DESTROY,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        },
+        family: libc::AF_INET as u8,
+        protocol: libc::IPPROTO_TCP as u8,
+        extensions: 0,
+        pad: 0,
+        states: !0,
+        id: SocketId {
+            source_port: pair.local.port,
+            destination_port: pair.remote.port,
+            source,
+            destination,
+            interface: 0,
+            cookie: [INET_DIAG_NOCOOKIE; 2],
+        },
+    };
+    let request = &request as *const DestroyRequest as *const libc::c_void;
+    if unsafe { libc::send(fd, request, mem::size_of::<DestroyRequest>(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // the kernel acknowledges with the error code, zero is success
+    let mut answer = [0u8; 0x100];
+    let received = unsafe { libc::recv(fd, answer.as_mut_ptr() as *mut libc::c_void, answer.len(), 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header_length = mem::size_of::<libc::nlmsghdr>();
+    if (received as usize) < header_length + 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "netlink answer is too short"));
+    }
+    let header = unsafe { ptr::read_unaligned(answer.as_ptr() as *const libc::nlmsghdr) };
+    if header.nlmsg_type != libc::NLMSG_ERROR as u16 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected netlink answer"));
+    }
+    match i32::from_ne_bytes(answer[header_length..(header_length + 4)].try_into().unwrap()) {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(-error)),
+    }
+}
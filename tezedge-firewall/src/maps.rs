@@ -0,0 +1,1026 @@
+use std::{
+    collections::{HashMap as StdHashMap, HashSet},
+    fmt,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use redbpf::{HashMap, Module};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use xdp_module::{
+    BlockingReason, BanFlags, Endpoint, EndpointPair, Ipv4Prefix, Connection, Status, Config, Counter,
+    Traffic, HANDSHAKES_STARTED, HANDSHAKES_FINISHED,
+};
+
+use tezedge_firewall_command::{Prefix, Trusted, PeerId};
+
+use super::{
+    liveness,
+    scope::{BanPolicy, BanScope},
+    peer_id::public_key_hash,
+    traffic::{self, TrafficTracker, TopReport},
+};
+
+/// the value of the maps which are used as a set
+type MapVoid = u32;
+
+#[derive(Debug)]
+pub enum MapError {
+    NotFound(&'static str),
+    Invalid(&'static str, redbpf::Error),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::NotFound(name) => write!(f, "map \"{}\" not found", name),
+            MapError::Invalid(name, e) => write!(f, "map \"{}\" is invalid: {:?}", name, e),
+        }
+    }
+}
+
+fn resolve<'a, K, V>(module: &'a Module, name: &'static str) -> Result<HashMap<'a, K, V>, MapError>
+where
+    K: Clone,
+    V: Clone,
+{
+    let base = module
+        .maps
+        .iter()
+        .find(|m| m.name == name)
+        .ok_or(MapError::NotFound(name))?;
+    HashMap::new(base).map_err(|e| MapError::Invalid(name, e))
+}
+
+fn ipv4_prefix(prefix: &Prefix) -> Option<Ipv4Prefix> {
+    match prefix.ip {
+        IpAddr::V4(ip) => Some(Ipv4Prefix::new(ip.octets(), prefix.length as u32)),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// the clock of `bpf_ktime_get_ns`
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64)
+}
+
+/// Typed handles of every map of the XDP program
+pub struct Maps<'a> {
+    blacklist: HashMap<'a, [u8; 4], BanFlags>,
+    prefix_blacklist: HashMap<'a, Ipv4Prefix, BanFlags>,
+    allow_ip: HashMap<'a, Ipv4Prefix, MapVoid>,
+    allow_pk: HashMap<'a, [u8; 32], MapVoid>,
+    deny_pk: HashMap<'a, [u8; 32], MapVoid>,
+    flow_blacklist: HashMap<'a, EndpointPair, MapVoid>,
+    peers: HashMap<'a, [u8; 32], Endpoint>,
+    pending_peers: HashMap<'a, Endpoint, MapVoid>,
+    node: HashMap<'a, u16, MapVoid>,
+    status: HashMap<'a, EndpointPair, Connection>,
+    config: HashMap<'a, u32, Config>,
+    counters: HashMap<'a, u32, u64>,
+    ip_connections: HashMap<'a, [u8; 4], u32>,
+    handshakes: HashMap<'a, u32, u64>,
+    verified: HashMap<'a, [u8; 32], MapVoid>,
+    traffic: HashMap<'a, EndpointPair, Traffic>,
+    bogons: HashMap<'a, Ipv4Prefix, u32>,
+    services: HashMap<'a, u16, MapVoid>,
+    service_allow: HashMap<'a, Ipv4Prefix, MapVoid>,
+    tunnels: HashMap<'a, Ipv4Prefix, MapVoid>,
+}
+
+impl<'a> Maps<'a> {
+    pub fn new(module: &'a Module) -> Result<Self, MapError> {
+        Ok(Maps {
+            blacklist: resolve(module, "blacklist")?,
+            prefix_blacklist: resolve(module, "prefix_blacklist")?,
+            allow_ip: resolve(module, "allow_ip")?,
+            allow_pk: resolve(module, "allow_pk")?,
+            deny_pk: resolve(module, "deny_pk")?,
+            flow_blacklist: resolve(module, "flow_blacklist")?,
+            peers: resolve(module, "peers")?,
+            pending_peers: resolve(module, "pending_peers")?,
+            node: resolve(module, "node")?,
+            status: resolve(module, "status")?,
+            config: resolve(module, "config")?,
+            counters: resolve(module, "counters")?,
+            ip_connections: resolve(module, "ip_connections")?,
+            handshakes: resolve(module, "handshakes")?,
+            verified: resolve(module, "verified")?,
+            traffic: resolve(module, "traffic")?,
+            bogons: resolve(module, "bogons")?,
+            services: resolve(module, "services")?,
+            service_allow: resolve(module, "service_allow")?,
+            tunnels: resolve(module, "tunnels")?,
+        })
+    }
+
+    pub fn set_config(&self, config: Config) {
+        self.config.set(0, config)
+    }
+
+    /// the XDP program only increments existing counters
+    pub fn reset_counters(&self) {
+        for counter in Counter::ALL.iter() {
+            self.counters.set(*counter as u32, 0);
+        }
+        self.handshakes.set(HANDSHAKES_STARTED, 0);
+        self.handshakes.set(HANDSHAKES_FINISHED, 0);
+    }
+
+    /// drop the traffic to the node from the network, `category` is the counter of dropped packets
+    pub fn add_bogon(&self, prefix: Ipv4Prefix, category: Counter) {
+        self.bogons.set(prefix, category as u32)
+    }
+
+    /// the addresses of the host are spoofed if they come from outside, forget the old ones
+    pub fn set_own_addresses(&self, addresses: Vec<[u8; 4]>, log: &slog::Logger) {
+        let current = self
+            .bogons
+            .iter()
+            .filter(|(prefix, category)| prefix.prefix_length == 32 && *category == Counter::Spoofed as u32)
+            .map(|(prefix, _)| prefix.ipv4)
+            .collect::<HashSet<_>>();
+        let addresses = addresses
+            .into_iter()
+            .filter(|ipv4| ipv4[0] != 127)
+            .collect::<HashSet<_>>();
+        for ipv4 in current.difference(&addresses) {
+            self.bogons.delete(Ipv4Prefix::host(*ipv4));
+        }
+        for ipv4 in addresses.difference(&current) {
+            slog::info!(log, "Drop the traffic to the node with own address as source: {:?}", Ipv4Prefix::host(*ipv4));
+            self.add_bogon(Ipv4Prefix::host(*ipv4), Counter::Spoofed);
+        }
+    }
+
+    /// the proof of work waits for verification
+    fn handshake_started(&self) {
+        let started = self.handshakes.get(HANDSHAKES_STARTED).unwrap_or(0);
+        self.handshakes.set(HANDSHAKES_STARTED, started + 1);
+    }
+
+    /// proof of work is checked, the peer with valid one is admitted even if too many handshakes are in flight
+    fn handshake_finished(&self, public_key: [u8; 32], valid: bool) {
+        let finished = self.handshakes.get(HANDSHAKES_FINISHED).unwrap_or(0);
+        self.handshakes.set(HANDSHAKES_FINISHED, finished + 1);
+        if valid {
+            self.verified.set(public_key, 0);
+        }
+    }
+
+    fn handshakes_in_flight(&self) -> u64 {
+        let started = self.handshakes.get(HANDSHAKES_STARTED).unwrap_or(0);
+        let finished = self.handshakes.get(HANDSHAKES_FINISHED).unwrap_or(0);
+        started.saturating_sub(finished)
+    }
+
+    /// the connection is established, but the peer has sent nothing during `deadline`,
+    /// it holds the socket of the node for free
+    fn kick_silent(&self, deadline: Duration, policy: &BanPolicy, log: &slog::Logger) {
+        let now = monotonic_ns();
+        let deadline = deadline.as_nanos() as u64;
+        let silent = self
+            .status
+            .iter()
+            .filter(|(_, connection)| {
+                connection.status.is_empty() && now.saturating_sub(connection.since) > deadline
+            })
+            .map(|(pair, _)| pair)
+            .collect::<Vec<_>>();
+        for pair in silent {
+            // the peer might have sent its first message meanwhile
+            let mut connection = match self.status.get(pair.clone()) {
+                Some(connection) if connection.status.is_empty() => connection,
+                _ => continue,
+            };
+            slog::info!(log, "Connection {:?} has sent nothing for too long", pair.remote);
+            connection.status.insert(Status::BLOCKED);
+            self.status.set(pair.clone(), connection);
+            self.close(&pair, log);
+            self.ban(pair, BlockingReason::HandshakeTimeout, policy, log);
+        }
+    }
+
+    /// the node must be filtered, so its port loses the protection given earlier
+    fn set_port(&self, port: u16, log: &slog::Logger) {
+        if self.services.get(port).is_some() {
+            slog::warn!(log, "Port {} of the node is not protected anymore", port);
+            self.services.delete(port);
+        }
+        self.node.set(port, 0)
+    }
+
+    /// the XDP program misses the connection closed silently, count live connections of every ip again
+    fn recount_ip_connections(&self, status: &[(EndpointPair, Connection)]) {
+        let ports = self
+            .node
+            .iter()
+            .map(|(port, _)| port.to_be_bytes())
+            .collect::<HashSet<_>>();
+        let mut counts = StdHashMap::new();
+        for (pair, connection) in status {
+            if ports.contains(&pair.local.port) && !connection.status.contains(Status::BLOCKED) {
+                *counts.entry(pair.remote.ipv4).or_insert(0) += 1;
+            }
+        }
+        let gone = self
+            .ip_connections
+            .iter()
+            .map(|(ipv4, _)| ipv4)
+            .filter(|ipv4| !counts.contains_key(ipv4))
+            .collect::<Vec<_>>();
+        for ipv4 in gone {
+            self.ip_connections.delete(ipv4);
+        }
+        for (ipv4, count) in counts {
+            self.ip_connections.set(ipv4, count);
+        }
+    }
+
+    /// the network or the peer bypasses every check
+    pub fn allow(&self, trusted: Trusted, log: &slog::Logger) {
+        slog::info!(log, "Allow {}", trusted);
+        match trusted {
+            Trusted::Network(prefix) => match ipv4_prefix(&prefix) {
+                Some(prefix) => self.allow_ip.set(prefix, 0),
+                None => slog::warn!(log, "Cannot allow {}, ipv6 is not supported", prefix),
+            },
+            Trusted::Peer(public_key) => self.allow_pk.set(public_key, 0),
+        }
+    }
+
+    pub fn disallow(&self, trusted: Trusted, log: &slog::Logger) {
+        slog::info!(log, "Disallow {}", trusted);
+        match trusted {
+            Trusted::Network(prefix) => match ipv4_prefix(&prefix) {
+                Some(prefix) => self.allow_ip.delete(prefix),
+                None => slog::warn!(log, "Cannot disallow {}, ipv6 is not supported", prefix),
+            },
+            Trusted::Peer(public_key) => self.allow_pk.delete(public_key),
+        }
+    }
+
+    /// only the sources allowed to services may connect to the port, the allowed source
+    /// would skip every check of the node, so the port of the node cannot be protected
+    pub fn protect(&self, port: u16, log: &slog::Logger) {
+        if self.node.get(port).is_some() {
+            slog::warn!(log, "Cannot protect port {}, it is the port of the node", port);
+            return;
+        }
+        slog::info!(log, "Protect port {}", port);
+        self.services.set(port, 0)
+    }
+
+    pub fn unprotect(&self, port: u16, log: &slog::Logger) {
+        slog::info!(log, "Unprotect port {}", port);
+        self.services.delete(port)
+    }
+
+    pub fn allow_service(&self, prefix: Prefix, log: &slog::Logger) {
+        slog::info!(log, "Allow {} to protected ports", prefix);
+        match ipv4_prefix(&prefix) {
+            Some(ipv4) => self.service_allow.set(ipv4, 0),
+            None => slog::warn!(log, "Cannot allow {}, ipv6 is not supported", prefix),
+        }
+    }
+
+    /// the tunnels from the endpoint are opened, their traffic is checked like any other
+    pub fn add_tunnel(&self, prefix: Prefix, log: &slog::Logger) {
+        slog::info!(log, "Open the tunnels from {}", prefix);
+        match ipv4_prefix(&prefix) {
+            Some(ipv4) => self.tunnels.set(ipv4, 0),
+            None => slog::warn!(log, "Cannot open the tunnels from {}, ipv6 is not supported", prefix),
+        }
+    }
+
+    pub fn disallow_service(&self, prefix: Prefix, log: &slog::Logger) {
+        slog::info!(log, "Disallow {} to protected ports", prefix);
+        match ipv4_prefix(&prefix) {
+            Some(ipv4) => self.service_allow.delete(ipv4),
+            None => slog::warn!(log, "Cannot disallow {}, ipv6 is not supported", prefix),
+        }
+    }
+
+    /// the longest prefix match finds the network which contains the ip
+    fn trusted(&self, ip: [u8; 4]) -> bool {
+        self.allow_ip.get(Ipv4Prefix::host(ip)).is_some()
+    }
+
+    /// `flags` limits the ban to the traffic of the node, or not
+    pub fn block(&self, ip: IpAddr, reason: BlockingReason, flags: BanFlags, log: &slog::Logger) {
+        if let IpAddr::V4(ip) = ip {
+            if self.trusted(ip.octets()) {
+                slog::info!(log, "Do not block trusted {}, reason: {:?}", ip, reason);
+                return;
+            }
+        }
+        // TODO: store reason somewhere in userspace
+        slog::info!(log, "Block {}, reason: {:?}, flags: {:?}", ip, reason, flags);
+        match ip {
+            IpAddr::V4(ip) => self.blacklist.set(ip.octets(), flags),
+            IpAddr::V6(ip) => slog::warn!(log, "Cannot block {}, ipv6 is not supported", ip),
+        }
+    }
+
+    /// ban the offending connection, its ip or its network, depending on the reason
+    fn ban(&self, pair: EndpointPair, reason: BlockingReason, policy: &BanPolicy, log: &slog::Logger) {
+        if self.trusted(pair.remote.ipv4) {
+            slog::info!(log, "Do not block trusted {:?}, reason: {:?}", pair.remote, reason);
+            return;
+        }
+        match policy.scope(&reason) {
+            BanScope::Flow => {
+                slog::info!(log, "Block {:?}, reason: {:?}", pair, reason);
+                self.flow_blacklist.set(pair, 0)
+            },
+            BanScope::Ip => self.block(IpAddr::from(pair.remote.ipv4), reason, policy.flags(), log),
+            BanScope::Prefix(length) => {
+                let prefix = Ipv4Prefix::new(pair.remote.ipv4, length as u32);
+                slog::info!(log, "Block {:?}, reason: {:?}, flags: {:?}", prefix, reason, policy.flags());
+                self.prefix_blacklist.set(prefix, policy.flags())
+            },
+        }
+    }
+
+    /// lift every ban which affects the ip, whatever its scope
+    pub fn unblock(&self, ip: IpAddr, log: &slog::Logger) {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(ip) => {
+                slog::warn!(log, "Cannot unblock {}, ipv6 is not supported", ip);
+                return;
+            },
+        };
+        self.blacklist.delete(ip);
+        let prefixes = self
+            .prefix_blacklist
+            .iter()
+            .map(|(prefix, _)| prefix)
+            .filter(|prefix| prefix.contains(ip))
+            .collect::<Vec<_>>();
+        for prefix in prefixes {
+            self.prefix_blacklist.delete(prefix);
+        }
+        let flows = self
+            .flow_blacklist
+            .iter()
+            .map(|(pair, _)| pair)
+            .filter(|pair| pair.remote.ipv4 == ip)
+            .collect::<Vec<_>>();
+        for pair in flows {
+            self.flow_blacklist.delete(pair);
+        }
+    }
+
+    /// never accept the identity, the peer id is resolved when its public key is seen
+    fn block_peer(&self, peer: PeerId, reason: String, denials: &mut Denials, log: &slog::Logger) {
+        match peer {
+            PeerId::PublicKey(public_key) => self.deny(public_key, reason, denials, log),
+            PeerId::Hash(hash) => {
+                let public_key = self
+                    .known_public_keys()
+                    .into_iter()
+                    .find(|public_key| public_key_hash(public_key) == hash);
+                match public_key {
+                    Some(public_key) => self.deny(public_key, reason, denials, log),
+                    None => {
+                        slog::info!(log, "Block peer {} when seen, reason: {}", hex::encode(hash), reason);
+                        denials.unresolved.insert(hash, reason);
+                    },
+                }
+            },
+        }
+    }
+
+    fn deny(&self, public_key: [u8; 32], reason: String, denials: &mut Denials, log: &slog::Logger) {
+        slog::info!(log, "Block peer {}, reason: {}", hex::encode(public_key), reason);
+        self.deny_pk.set(public_key, 0);
+        denials.reasons.insert(public_key, reason);
+        // the identity might be connected now
+        let pairs = self
+            .status
+            .iter()
+            .filter(|(_, connection)| connection.public_key == public_key)
+            .map(|(pair, _)| pair)
+            .collect::<Vec<_>>();
+        for pair in pairs {
+            self.close(&pair, log);
+        }
+    }
+
+    /// close the socket of the node, the peer sees the connection reset
+    fn close(&self, pair: &EndpointPair, log: &slog::Logger) {
+        match liveness::destroy(pair) {
+            Ok(()) => slog::info!(log, "Closed the connection {:?}", pair.remote),
+            Err(e) => slog::warn!(log, "Cannot close the connection {:?}: {}", pair.remote, e),
+        }
+    }
+
+    fn unblock_peer(&self, peer: PeerId, denials: &mut Denials, log: &slog::Logger) {
+        let public_keys = match peer {
+            PeerId::PublicKey(public_key) => vec![public_key],
+            PeerId::Hash(hash) => {
+                denials.unresolved.remove(&hash);
+                self.deny_pk
+                    .iter()
+                    .map(|(public_key, _)| public_key)
+                    .filter(|public_key| public_key_hash(public_key) == hash)
+                    .collect()
+            },
+        };
+        for public_key in public_keys {
+            self.deny_pk.delete(public_key);
+            if let Some(reason) = denials.reasons.remove(&public_key) {
+                slog::info!(log, "Unblock peer {}, was blocked for: {}", hex::encode(public_key), reason);
+            }
+        }
+    }
+
+    /// public keys of the peers connected now
+    fn known_public_keys(&self) -> HashSet<[u8; 32]> {
+        let mut public_keys = self
+            .peers
+            .iter()
+            .map(|(public_key, _)| public_key)
+            .collect::<HashSet<_>>();
+        public_keys.extend(
+            self.status
+                .iter()
+                .map(|(_, connection)| connection.public_key)
+                .filter(|public_key| *public_key != [0; 32]),
+        );
+        public_keys
+    }
+
+    /// deny the peers whose public key has appeared since they were blocked by peer id,
+    /// the peers verified in the kernel are not reported, so they are found here
+    fn resolve_denials(&self, denials: &mut Denials, log: &slog::Logger) {
+        if denials.unresolved.is_empty() {
+            return;
+        }
+        for public_key in self.known_public_keys() {
+            self.resolve_denial(public_key, denials, log);
+        }
+    }
+
+    /// the public key is seen the first time, deny it if it is blocked by peer id
+    fn resolve_denial(&self, public_key: [u8; 32], denials: &mut Denials, log: &slog::Logger) {
+        if denials.unresolved.is_empty() {
+            return;
+        }
+        if let Some(reason) = denials.unresolved.remove(&public_key_hash(&public_key)) {
+            self.deny(public_key, reason, denials, log);
+        }
+    }
+
+    /// forget everything about the connection the node reports closed
+    fn disconnected(&self, endpoint: Endpoint, public_key: [u8; 32], ages: &mut Ages) {
+        self.pending_peers.delete(endpoint.clone());
+        ages.pending_peers.remove(&endpoint);
+        // the peer might be connected again from another endpoint, the same as the XDP program does
+        if self.peers.get(public_key) == Some(endpoint.clone()) {
+            self.peers.delete(public_key);
+            ages.peers.remove(&public_key);
+        }
+        let pairs = self
+            .status
+            .iter()
+            .map(|(pair, _)| pair)
+            .filter(|pair| pair.remote == endpoint)
+            .collect::<Vec<_>>();
+        for pair in pairs {
+            self.status.delete(pair.clone());
+            ages.status.remove(&pair);
+        }
+    }
+
+    /// the connection which holds the public key is dead, let the new one take it,
+    /// the new connection is checked again when the peer retransmits the dropped payload
+    fn replace_peer(&self, public_key: [u8; 32], pair: EndpointPair, ages: &mut Ages) {
+        self.peers.set(public_key, pair.remote.clone());
+        ages.peers.remove(&public_key);
+        self.status.delete(pair.clone());
+        ages.status.remove(&pair);
+    }
+
+    /// the node reports every peer it is connected to, forget other peers,
+    /// they are left from the previous run of the node, the pending outgoing connections
+    /// might be still dialing, so they are swept as usual
+    fn resync(&self, connected: Vec<(Endpoint, [u8; 32])>, ages: &mut Ages, log: &slog::Logger) {
+        let connected = connected
+            .into_iter()
+            .map(|(endpoint, public_key)| (public_key, endpoint))
+            .collect::<StdHashMap<_, _>>();
+
+        let stale_peers = self
+            .peers
+            .iter()
+            .filter(|(public_key, endpoint)| connected.get(public_key) != Some(endpoint))
+            .map(|(public_key, _)| public_key)
+            .collect::<Vec<_>>();
+        slog::info!(
+            log,
+            "Resynchronize with the node, connected: {}, stale peers: {}",
+            connected.len(),
+            stale_peers.len()
+        );
+
+        for public_key in stale_peers {
+            self.peers.delete(public_key);
+            ages.peers.remove(&public_key);
+        }
+        let now = Instant::now();
+        for (public_key, endpoint) in connected {
+            self.peers.set(public_key, endpoint);
+            ages.peers.insert(public_key, now);
+        }
+    }
+
+    /// the node is restarted, it has no peers anymore, the pending outgoing connections
+    /// are swept as usual
+    fn forget_peers(&self, ages: &mut Ages, log: &slog::Logger) {
+        let peers = self.peers.iter().map(|(public_key, _)| public_key).collect::<Vec<_>>();
+        slog::info!(log, "The node is restarted, forget {} peers", peers.len());
+        for public_key in peers {
+            self.peers.delete(public_key);
+        }
+        ages.peers.clear();
+    }
+
+    /// The kernel does not remove entries if it has not seen the connection closing,
+    /// remove entries which have been candidates for longer than `ttl`: the blocked connections
+    /// of `status`, the peers and the pending outgoing connections whose endpoint has no entry
+    /// in `status`, a peer reconnected from another endpoint is kept while that one lives
+    fn sweep(&self, ages: &mut Ages, ttl: Duration) {
+        let now = Instant::now();
+        ages.stats.sweeps += 1;
+
+        let status = self.status.iter().collect::<Vec<_>>();
+        // blocked connection is useless, its ip is blacklisted
+        let blocked = status
+            .iter()
+            .filter(|(_, connection)| connection.status.contains(Status::BLOCKED))
+            .map(|(pair, _)| pair.clone());
+        for pair in Ages::expired(&mut ages.status, blocked, now, ttl) {
+            self.status.delete(pair);
+            ages.stats.status += 1;
+        }
+
+        self.recount_ip_connections(&status);
+
+        let live = status
+            .into_iter()
+            .map(|(pair, _)| pair.remote)
+            .collect::<HashSet<_>>();
+
+        let peers = self
+            .peers
+            .iter()
+            .filter(|(_, endpoint)| !live.contains(endpoint))
+            .map(|(public_key, _)| public_key);
+        for public_key in Ages::expired(&mut ages.peers, peers, now, ttl) {
+            self.peers.delete(public_key);
+            ages.stats.peers += 1;
+        }
+
+        // outgoing connection which never happened
+        let pending_peers = self
+            .pending_peers
+            .iter()
+            .map(|(endpoint, _)| endpoint)
+            .filter(|endpoint| !live.contains(endpoint));
+        for endpoint in Ages::expired(&mut ages.pending_peers, pending_peers, now, ttl) {
+            self.pending_peers.delete(endpoint);
+            ages.stats.pending_peers += 1;
+        }
+    }
+
+    /// a minute of traffic, the kernel forgets idle closed connections
+    fn sample_traffic(&self, tracker: &mut TrafficTracker) {
+        let public_keys = self
+            .status
+            .iter()
+            .filter(|(_, connection)| connection.public_key != [0; 32])
+            .map(|(pair, connection)| (pair, connection.public_key))
+            .collect::<StdHashMap<_, _>>();
+        let flows = self.traffic.iter().map(|(pair, traffic)| {
+            let public_key = public_keys.get(&pair).cloned();
+            (pair, traffic, public_key)
+        });
+        for pair in tracker.record(flows.collect::<Vec<_>>()) {
+            self.traffic.delete(pair);
+        }
+    }
+
+    fn summary(&self, ages: &Ages, denials: &Denials) -> MapSummary {
+        MapSummary {
+            blacklist: self.blacklist.iter().count(),
+            prefix_blacklist: self.prefix_blacklist.iter().count(),
+            flow_blacklist: self.flow_blacklist.iter().count(),
+            allow_ip: self.allow_ip.iter().count(),
+            allow_pk: self.allow_pk.iter().count(),
+            deny_pk: self.deny_pk.iter().count(),
+            unresolved_peer_ids: denials.unresolved.len(),
+            peers: self.peers.iter().count(),
+            pending_peers: self.pending_peers.iter().count(),
+            status: self.status.iter().count(),
+            swept: ages.stats.clone(),
+            handshakes_in_flight: self.handshakes_in_flight(),
+            services: self.services.iter().map(|(port, _)| port).collect(),
+            service_allow: self.service_allow.iter().count(),
+            counters: Counter::ALL
+                .iter()
+                .map(|counter| (*counter, self.counters.get(*counter as u32).unwrap_or(0)))
+                .collect(),
+        }
+    }
+
+    fn apply(
+        &self,
+        command: MapCommand,
+        ages: &mut Ages,
+        denials: &mut Denials,
+        traffic: &mut TrafficTracker,
+        policy: &BanPolicy,
+        log: &slog::Logger,
+    ) {
+        match command {
+            MapCommand::Block(ip, reason) => self.block(ip, reason, policy.flags(), log),
+            MapCommand::Ban(pair, reason) => self.ban(pair, reason, policy, log),
+            MapCommand::Allow(trusted) => self.allow(trusted, log),
+            MapCommand::Disallow(trusted) => self.disallow(trusted, log),
+            MapCommand::Protect(port) => self.protect(port, log),
+            MapCommand::Unprotect(port) => self.unprotect(port, log),
+            MapCommand::AllowService(prefix) => self.allow_service(prefix, log),
+            MapCommand::DisallowService(prefix) => self.disallow_service(prefix, log),
+            MapCommand::BlockPeer(peer, reason) => self.block_peer(peer, reason, denials, log),
+            MapCommand::UnblockPeer(peer) => self.unblock_peer(peer, denials, log),
+            MapCommand::PeerSeen(public_key) => self.resolve_denial(public_key, denials, log),
+            MapCommand::Unblock(ip) => self.unblock(ip, log),
+            MapCommand::SetPort(port) => self.set_port(port, log),
+            MapCommand::Resync(connected) => self.resync(connected, ages, log),
+            MapCommand::ForgetPeers => self.forget_peers(ages, log),
+            MapCommand::AddPending(endpoint) => {
+                self.pending_peers.set(endpoint.clone(), 0);
+                ages.pending_peers.insert(endpoint, Instant::now());
+            },
+            MapCommand::Disconnected(endpoint, public_key) => {
+                self.disconnected(endpoint, public_key, ages)
+            },
+            MapCommand::ReplacePeer(public_key, pair) => self.replace_peer(public_key, pair, ages),
+            MapCommand::HandshakeStarted => self.handshake_started(),
+            MapCommand::HandshakeFinished(public_key, valid) => self.handshake_finished(public_key, valid),
+            MapCommand::KickSilent(deadline) => self.kick_silent(deadline, policy, log),
+            MapCommand::Sweep(ttl) => {
+                self.sweep(ages, ttl);
+                self.resolve_denials(denials, log)
+            },
+            MapCommand::Summary(tx) => {
+                let _ = tx.send(self.summary(ages, denials));
+            },
+            MapCommand::SampleTraffic => self.sample_traffic(traffic),
+            MapCommand::OwnAddresses(addresses) => self.set_own_addresses(addresses, log),
+            MapCommand::Top(count, tx) => {
+                let _ = tx.send(traffic.top(count));
+            },
+        }
+    }
+
+    async fn run(self, rx: mpsc::Receiver<MapCommand>, policy: BanPolicy, log: slog::Logger) {
+        let mut rx = rx;
+        let mut ages = Ages::default();
+        let mut denials = Denials::default();
+        let mut traffic = TrafficTracker::default();
+        while let Some(command) = rx.recv().await {
+            self.apply(command, &mut ages, &mut denials, &mut traffic, &policy, &log);
+            // apply everything queued meanwhile without yielding
+            let mut batch = 1;
+            while let Ok(command) = rx.try_recv() {
+                self.apply(command, &mut ages, &mut denials, &mut traffic, &policy, &log);
+                batch += 1;
+            }
+            slog::debug!(log, "Applied {} map commands", batch);
+        }
+    }
+}
+
+/// When userspace has seen the map entries the first time, the kernel does not record it,
+/// so an entry created by the XDP program is aged since the first sweep that met it
+#[derive(Default)]
+struct Ages {
+    pending_peers: StdHashMap<Endpoint, Instant>,
+    peers: StdHashMap<[u8; 32], Instant>,
+    status: StdHashMap<EndpointPair, Instant>,
+    stats: SweepStats,
+}
+
+impl Ages {
+    /// returns candidates older than `ttl`, forgets the age of everything else,
+    /// the entry might be gone, or it is not a candidate anymore
+    fn expired<K, I>(ages: &mut StdHashMap<K, Instant>, candidates: I, now: Instant, ttl: Duration) -> Vec<K>
+    where
+        K: Clone + Eq + Hash,
+        I: Iterator<Item = K>,
+    {
+        let candidates = candidates
+            .map(|key| {
+                let since = ages.get(&key).cloned().unwrap_or(now);
+                (key, since)
+            })
+            .collect::<Vec<_>>();
+        ages.clear();
+        candidates
+            .into_iter()
+            .filter_map(|(key, since)| {
+                if now.duration_since(since) > ttl {
+                    Some(key)
+                } else {
+                    ages.insert(key, since);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The reasons of the denied identities, and the peer ids whose public key is not seen yet
+#[derive(Default)]
+struct Denials {
+    reasons: StdHashMap<[u8; 32], String>,
+    unresolved: StdHashMap<[u8; 16], String>,
+}
+
+/// How many entries the sweeper has removed
+#[derive(Default, Clone, Debug)]
+pub struct SweepStats {
+    pub sweeps: u64,
+    pub pending_peers: u64,
+    pub peers: u64,
+    pub status: u64,
+}
+
+#[derive(Debug)]
+enum MapCommand {
+    Block(IpAddr, BlockingReason),
+    Ban(EndpointPair, BlockingReason),
+    Allow(Trusted),
+    Disallow(Trusted),
+    Protect(u16),
+    Unprotect(u16),
+    AllowService(Prefix),
+    DisallowService(Prefix),
+    BlockPeer(PeerId, String),
+    UnblockPeer(PeerId),
+    PeerSeen([u8; 32]),
+    Unblock(IpAddr),
+    SetPort(u16),
+    AddPending(Endpoint),
+    Disconnected(Endpoint, [u8; 32]),
+    ReplacePeer([u8; 32], EndpointPair),
+    Resync(Vec<(Endpoint, [u8; 32])>),
+    ForgetPeers,
+    HandshakeStarted,
+    HandshakeFinished([u8; 32], bool),
+    KickSilent(Duration),
+    Sweep(Duration),
+    Summary(oneshot::Sender<MapSummary>),
+    SampleTraffic,
+    OwnAddresses(Vec<[u8; 4]>),
+    Top(usize, oneshot::Sender<TopReport>),
+}
+
+/// How many entries every map has
+#[derive(Debug)]
+pub struct MapSummary {
+    pub blacklist: usize,
+    pub prefix_blacklist: usize,
+    pub flow_blacklist: usize,
+    pub allow_ip: usize,
+    pub allow_pk: usize,
+    pub deny_pk: usize,
+    pub unresolved_peer_ids: usize,
+    pub peers: usize,
+    pub pending_peers: usize,
+    pub status: usize,
+    pub swept: SweepStats,
+    pub handshakes_in_flight: u64,
+    pub services: Vec<u16>,
+    pub service_allow: usize,
+    pub counters: Vec<(Counter, u64)>,
+}
+
+impl fmt::Display for MapSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "maps: blacklist: {}, prefix blacklist: {}, flow blacklist: {}, trusted networks: {}, trusted peers: {}, denied peers: {}, unresolved peer ids: {}, peers: {}, pending peers: {}, status: {}",
+            self.blacklist,
+            self.prefix_blacklist,
+            self.flow_blacklist,
+            self.allow_ip,
+            self.allow_pk,
+            self.deny_pk,
+            self.unresolved_peer_ids,
+            self.peers,
+            self.pending_peers,
+            self.status
+        )?;
+        writeln!(
+            f,
+            "sweeper: sweeps: {}, removed pending peers: {}, peers: {}, status: {}",
+            self.swept.sweeps, self.swept.pending_peers, self.swept.peers, self.swept.status
+        )?;
+        writeln!(f, "handshakes in flight: {}", self.handshakes_in_flight)?;
+        writeln!(
+            f,
+            "protected ports: {:?}, allowed networks: {}",
+            self.services, self.service_allow
+        )?;
+        write!(f, "dropped:")?;
+        for (i, (counter, value)) in self.counters.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(f, "{} {}: {}", separator, counter.name(), value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The only owner of the maps is a dedicated task, everybody else sends it commands through this handle
+#[derive(Clone)]
+pub struct MapHandle {
+    tx: mpsc::Sender<MapCommand>,
+}
+
+impl MapHandle {
+    /// the maps should be validated by `Maps::new` before the program is attached,
+    /// stale entries are swept every `sweep_interval`, `policy` decides the scope of bans,
+    /// connections silent for `handshake_deadline` are kicked, zero disables it
+    pub fn spawn(
+        module: Module,
+        policy: BanPolicy,
+        sweep_interval: Duration,
+        ttl: Duration,
+        handshake_deadline: Duration,
+        log: &slog::Logger,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(0x1000);
+        {
+            let log = log.clone();
+            tokio::spawn(async move {
+                let maps = Maps::new(&module).expect("maps are validated at startup");
+                maps.run(rx, policy, log).await
+            });
+        }
+
+        let handle = MapHandle { tx };
+        {
+            let mut handle = handle.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(sweep_interval);
+                loop {
+                    interval.tick().await;
+                    handle.send(MapCommand::Sweep(ttl)).await;
+                }
+            });
+        }
+        {
+            let mut handle = handle.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval_at(
+                    time::Instant::now() + traffic::SAMPLE_INTERVAL,
+                    traffic::SAMPLE_INTERVAL,
+                );
+                loop {
+                    interval.tick().await;
+                    handle.send(MapCommand::SampleTraffic).await;
+                }
+            });
+        }
+        if handshake_deadline != Duration::from_secs(0) {
+            let mut handle = handle.clone();
+            tokio::spawn(async move {
+                let period = (handshake_deadline / 2).max(Duration::from_secs(1));
+                let mut interval = time::interval(period);
+                loop {
+                    interval.tick().await;
+                    handle.send(MapCommand::KickSilent(handshake_deadline)).await;
+                }
+            });
+        }
+        handle
+    }
+
+    async fn send(&mut self, command: MapCommand) {
+        // the owner lives as long as the firewall, nothing to do if it is gone
+        let _ = self.tx.send(command).await;
+    }
+
+    pub async fn block(&mut self, ip: IpAddr, reason: BlockingReason) {
+        self.send(MapCommand::Block(ip, reason)).await
+    }
+
+    /// ban the misbehaving connection in the scope configured for the reason
+    pub async fn ban(&mut self, pair: EndpointPair, reason: BlockingReason) {
+        self.send(MapCommand::Ban(pair, reason)).await
+    }
+
+    pub async fn allow(&mut self, trusted: Trusted) {
+        self.send(MapCommand::Allow(trusted)).await
+    }
+
+    pub async fn disallow(&mut self, trusted: Trusted) {
+        self.send(MapCommand::Disallow(trusted)).await
+    }
+
+    pub async fn protect(&mut self, port: u16) {
+        self.send(MapCommand::Protect(port)).await
+    }
+
+    pub async fn unprotect(&mut self, port: u16) {
+        self.send(MapCommand::Unprotect(port)).await
+    }
+
+    pub async fn allow_service(&mut self, prefix: Prefix) {
+        self.send(MapCommand::AllowService(prefix)).await
+    }
+
+    pub async fn disallow_service(&mut self, prefix: Prefix) {
+        self.send(MapCommand::DisallowService(prefix)).await
+    }
+
+    pub async fn block_peer(&mut self, peer: PeerId, reason: String) {
+        self.send(MapCommand::BlockPeer(peer, reason)).await
+    }
+
+    pub async fn unblock_peer(&mut self, peer: PeerId) {
+        self.send(MapCommand::UnblockPeer(peer)).await
+    }
+
+    /// the XDP program has reported the public key
+    pub async fn peer_seen(&mut self, public_key: [u8; 32]) {
+        self.send(MapCommand::PeerSeen(public_key)).await
+    }
+
+    pub async fn unblock(&mut self, ip: IpAddr) {
+        self.send(MapCommand::Unblock(ip)).await
+    }
+
+    pub async fn set_port(&mut self, port: u16) {
+        self.send(MapCommand::SetPort(port)).await
+    }
+
+    pub async fn add_pending(&mut self, endpoint: Endpoint) {
+        self.send(MapCommand::AddPending(endpoint)).await
+    }
+
+    pub async fn disconnected(&mut self, endpoint: Endpoint, public_key: [u8; 32]) {
+        self.send(MapCommand::Disconnected(endpoint, public_key)).await
+    }
+
+    pub async fn replace_peer(&mut self, public_key: [u8; 32], pair: EndpointPair) {
+        self.send(MapCommand::ReplacePeer(public_key, pair)).await
+    }
+
+    pub async fn resync(&mut self, connected: Vec<(Endpoint, [u8; 32])>) {
+        self.send(MapCommand::Resync(connected)).await
+    }
+
+    /// the node is restarted
+    pub async fn forget_peers(&mut self) {
+        self.send(MapCommand::ForgetPeers).await
+    }
+
+    pub async fn handshake_started(&mut self) {
+        self.send(MapCommand::HandshakeStarted).await
+    }
+
+    pub async fn handshake_finished(&mut self, public_key: [u8; 32], valid: bool) {
+        self.send(MapCommand::HandshakeFinished(public_key, valid)).await
+    }
+
+    pub async fn summary(&mut self) -> Option<MapSummary> {
+        let (tx, rx) = oneshot::channel();
+        self.send(MapCommand::Summary(tx)).await;
+        rx.await.ok()
+    }
+
+    pub async fn own_addresses(&mut self, addresses: Vec<[u8; 4]>) {
+        self.send(MapCommand::OwnAddresses(addresses)).await
+    }
+
+    /// the biggest talkers of the last minute, hour and day
+    pub async fn top(&mut self, count: usize) -> Option<TopReport> {
+        let (tx, rx) = oneshot::channel();
+        self.send(MapCommand::Top(count, tx)).await;
+        rx.await.ok()
+    }
+}
@@ -0,0 +1,49 @@
+use std::convert::TryFrom;
+use crypto::{blake2b, hash::HashType};
+
+use tezedge_firewall_command::PeerId;
+
+/// Parses the public key in hex, or the peer id `idt...`
+pub fn parse(s: &str) -> Result<PeerId, String> {
+    let mut public_key = [0; 32];
+    if hex::decode_to_slice(s, &mut public_key).is_ok() {
+        return Ok(PeerId::PublicKey(public_key));
+    }
+    HashType::CryptoboxPublicKeyHash
+        .b58check_to_hash(s)
+        .ok()
+        .and_then(|hash| <[u8; 16]>::try_from(hash.as_slice()).ok())
+        .map(PeerId::Hash)
+        .ok_or_else(|| format!("expected public key in hex or peer id, got: {}", s))
+}
+
+/// The hash which the peer id encodes
+pub fn public_key_hash(public_key: &[u8; 32]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    hash.clone_from_slice(&blake2b::digest_128(public_key.as_ref()));
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use tezedge_firewall_command::PeerId;
+    use super::{parse, public_key_hash};
+
+    #[test]
+    fn peer_id() {
+        // the identity of the tester
+        let public_key = "7e8108e598b056b52cb430ee0e5e7ffd080b1b6bd9c9ad17dd9c44e2ced7fd75";
+        let hash = [
+            0xce, 0xfb, 0xc7, 0xec, 0x23, 0xe9, 0xf0, 0x08, 0x85, 0x02, 0x7a, 0xa8, 0x48, 0x79, 0x38,
+            0x84,
+        ];
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(public_key, &mut bytes).unwrap();
+        assert_eq!(public_key_hash(&bytes), hash);
+        assert_eq!(parse(public_key), Ok(PeerId::PublicKey(bytes)));
+        assert_eq!(parse("idtJunqYgD1M6r6o2qvGpiD5xKZWRu"), Ok(PeerId::Hash(hash)));
+        // the checksum is broken
+        assert!(parse("idtJunqYgD1M6r6o2qvGpiD5xKZWRv").is_err());
+        assert!(parse("not a peer").is_err());
+    }
+}
@@ -0,0 +1,46 @@
+/// Converts the float complexity into 256 bit big endian number,
+/// the same as `make_target` in tezos `crypto_box.ml`
+pub fn make_target(target: f64) -> [u8; 32] {
+    let target = target.max(0.0).min(256.0);
+    let shift = target.trunc();
+    let frac = target - shift;
+    let m = if frac == 0.0 {
+        (1u64 << 48) - 1
+    } else {
+        2.0f64.powf(48.0 - frac) as u64
+    };
+
+    // `m << (208 - shift)`, bits shifted outside of 256 bit are lost
+    let mut r = [0; 32];
+    for bit in 0..64 {
+        if m & (1 << bit) != 0 {
+            let position = bit + 208 - (shift as i32);
+            if (0..256).contains(&position) {
+                let position = position as usize;
+                r[31 - position / 8] |= 1 << (position % 8);
+            }
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::make_target;
+
+    #[test]
+    fn target() {
+        let mut expected = [0; 32];
+        expected[..6].clone_from_slice(&[0xff; 6]);
+        assert_eq!(make_target(0.0), expected);
+
+        let mut expected = [0; 32];
+        expected[3..9].clone_from_slice(&[0xff; 6]);
+        assert_eq!(make_target(24.0), expected);
+
+        // 2 ^ 47.5 is 0xb504f333f9de
+        let mut expected = [0; 32];
+        expected[3..9].clone_from_slice(&[0xb5, 0x04, 0xf3, 0x33, 0xf9, 0xde]);
+        assert_eq!(make_target(24.5), expected);
+    }
+}
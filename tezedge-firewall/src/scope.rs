@@ -0,0 +1,176 @@
+use std::{fmt, str::FromStr};
+
+use xdp_module::{BlockingReason, BanFlags};
+
+/// What to ban when the peer misbehaves, many honest peers might share the ip behind NAT
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BanScope {
+    /// only the offending connection
+    Flow,
+    /// the source ip
+    Ip,
+    /// the network of the source ip, the number is the prefix length
+    Prefix(u8),
+}
+
+/// the shortest prefix to ban, a shorter one would cut off a good part of the internet
+const PREFIX_LENGTH_MIN: u8 = 8;
+
+impl FromStr for BanScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flow" => Ok(BanScope::Flow),
+            "ip" => Ok(BanScope::Ip),
+            s => match s.strip_prefix("prefix/").map(str::parse::<u8>) {
+                Some(Ok(length)) if (PREFIX_LENGTH_MIN..=32).contains(&length) => Ok(BanScope::Prefix(length)),
+                _ => Err(format!(
+                    "unknown ban scope: {}, expected flow, ip or prefix/<length>, the length is {} to 32",
+                    s, PREFIX_LENGTH_MIN
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BanScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanScope::Flow => write!(f, "flow"),
+            BanScope::Ip => write!(f, "ip"),
+            BanScope::Prefix(length) => write!(f, "prefix/{}", length),
+        }
+    }
+}
+
+/// Which traffic of the banned ip or network is dropped
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BanTraffic {
+    /// the traffic of the node only, ssh, dns and monitoring on the same host keep working
+    Node,
+    /// everything
+    All,
+}
+
+impl FromStr for BanTraffic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "node" => Ok(BanTraffic::Node),
+            "all" => Ok(BanTraffic::All),
+            s => Err(format!("unknown banned traffic: {}, expected node or all", s)),
+        }
+    }
+}
+
+/// The reasons the firewall finds by itself, the other reasons always ban the ip
+const REASONS: [(&str, BlockingReason); 8] = [
+    ("bad-proof-of-work", BlockingReason::BadProofOfWork),
+    ("already-connected", BlockingReason::AlreadyConnected),
+    ("not-tezos-traffic", BlockingReason::NotTezosTraffic),
+    ("overloaded", BlockingReason::Overloaded),
+    ("identity-churn", BlockingReason::IdentityChurn),
+    ("handshake-timeout", BlockingReason::HandshakeTimeout),
+    ("quota-exceeded", BlockingReason::QuotaExceeded),
+    ("port-scan", BlockingReason::PortScan),
+];
+
+/// The scope for a single reason, written as `reason=scope`
+#[derive(Debug, Clone)]
+pub struct ScopeRule {
+    pub reason: BlockingReason,
+    pub scope: BanScope,
+}
+
+impl FromStr for ScopeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let (reason, scope) = match (parts.next(), parts.next()) {
+            (Some(reason), Some(scope)) => (reason, scope),
+            _ => return Err(format!("expected reason=scope, got: {}", s)),
+        };
+        let reason = REASONS
+            .iter()
+            .find(|(name, _)| *name == reason)
+            .map(|(_, reason)| reason.clone())
+            .ok_or_else(|| {
+                let names = REASONS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                format!("unknown reason: {}, expected one of: {}", reason, names.join(", "))
+            })?;
+        Ok(ScopeRule {
+            reason,
+            scope: scope.parse()?,
+        })
+    }
+}
+
+/// The scope for every reason, the default is the ip, but the flow for the handshake timeout
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    rules: Vec<ScopeRule>,
+    traffic: BanTraffic,
+}
+
+impl BanPolicy {
+    pub fn new(rules: Vec<ScopeRule>, traffic: BanTraffic) -> Self {
+        BanPolicy { rules, traffic }
+    }
+
+    /// the flags of the ip or prefix ban
+    pub fn flags(&self) -> BanFlags {
+        match self.traffic {
+            BanTraffic::Node => BanFlags::NODE_ONLY,
+            BanTraffic::All => BanFlags::empty(),
+        }
+    }
+
+    /// the last rule for the reason wins
+    pub fn scope(&self, reason: &BlockingReason) -> BanScope {
+        // a slow client should not cut off its neighbours behind NAT
+        let default = match reason {
+            BlockingReason::HandshakeTimeout => BanScope::Flow,
+            _ => BanScope::Ip,
+        };
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.reason.eq(reason))
+            .map(|rule| rule.scope)
+            .unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xdp_module::{BlockingReason, BanFlags};
+    use super::{BanPolicy, BanScope, BanTraffic, ScopeRule};
+
+    #[test]
+    fn parse_rules() {
+        let rules = ["not-tezos-traffic=flow", "bad-proof-of-work=prefix/24", "not-tezos-traffic=ip"]
+            .iter()
+            .map(|s| s.parse::<ScopeRule>().unwrap())
+            .collect();
+        let policy = BanPolicy::new(rules, "node".parse().unwrap());
+        assert_eq!(policy.flags(), BanFlags::NODE_ONLY);
+        assert_eq!(BanPolicy::new(Vec::new(), BanTraffic::All).flags(), BanFlags::empty());
+        assert_eq!(policy.scope(&BlockingReason::NotTezosTraffic), BanScope::Ip);
+        assert_eq!(policy.scope(&BlockingReason::BadProofOfWork), BanScope::Prefix(24));
+        assert_eq!(policy.scope(&BlockingReason::Overloaded), BanScope::Ip);
+        assert_eq!(policy.scope(&BlockingReason::HandshakeTimeout), BanScope::Flow);
+        let rules = vec!["handshake-timeout=ip".parse().unwrap()];
+        let policy = BanPolicy::new(rules, BanTraffic::All);
+        assert_eq!(policy.scope(&BlockingReason::HandshakeTimeout), BanScope::Ip);
+
+        assert!("bad-proof-of-work=prefix/33".parse::<ScopeRule>().is_err());
+        assert!("bad-proof-of-work=prefix/0".parse::<ScopeRule>().is_err());
+        assert!("bad-proof-of-work=prefix/7".parse::<ScopeRule>().is_err());
+        assert_eq!("prefix/8".parse::<BanScope>(), Ok(BanScope::Prefix(8)));
+        assert!("command-line-argument=flow".parse::<ScopeRule>().is_err());
+        assert!("overloaded".parse::<ScopeRule>().is_err());
+    }
+}
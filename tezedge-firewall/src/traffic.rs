@@ -0,0 +1,216 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use xdp_module::{EndpointPair, Traffic};
+
+/// How often the traffic counters of the kernel are sampled, every sample is a minute of traffic
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+const MINUTES: usize = 60;
+/// the current hour is in the minutes, so the day is 23 full hours and the current one
+const HOURS: usize = 23;
+
+/// Who sends the traffic
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Talker {
+    Ip([u8; 4]),
+    Peer([u8; 32]),
+}
+
+impl fmt::Display for Talker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Talker::Ip(ip) => write!(f, "ip {}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]),
+            Talker::Peer(public_key) => write!(f, "peer {}", hex::encode(public_key)),
+        }
+    }
+}
+
+type Sample = HashMap<Talker, Traffic>;
+
+fn add(total: &mut Traffic, traffic: &Traffic) {
+    total.bytes += traffic.bytes;
+    total.packets += traffic.packets;
+}
+
+fn merge<'a, I>(samples: I) -> Sample
+where
+    I: Iterator<Item = &'a Sample>,
+{
+    let mut total = Sample::new();
+    for sample in samples {
+        for (talker, traffic) in sample {
+            add(total.entry(*talker).or_default(), traffic);
+        }
+    }
+    total
+}
+
+/// Turns the growing counters of every connection into the traffic of every ip and public key
+/// during the last minute, hour and day
+#[derive(Default)]
+pub struct TrafficTracker {
+    /// the counters of every connection at the previous sample, and its public key if known
+    last: HashMap<EndpointPair, (Traffic, Option<[u8; 32]>)>,
+    minutes: VecDeque<Sample>,
+    hours: VecDeque<Sample>,
+    /// the minutes not rolled up into the hours yet
+    since_rollup: usize,
+}
+
+impl TrafficTracker {
+    /// the current counters of every connection, returns the connections which sent nothing
+    /// since the previous sample and have no public key anymore, the kernel can forget them
+    pub fn record<I>(&mut self, flows: I) -> Vec<EndpointPair>
+    where
+        I: IntoIterator<Item = (EndpointPair, Traffic, Option<[u8; 32]>)>,
+    {
+        let mut sample = Sample::new();
+        let mut idle = Vec::new();
+        let mut last = HashMap::new();
+        for (pair, traffic, public_key) in flows {
+            let (previous, known_key) = self
+                .last
+                .remove(&pair)
+                .unwrap_or((Traffic::default(), None));
+            // the counters are smaller if the kernel has evicted the entry and created it again
+            let delta = if traffic.bytes >= previous.bytes && traffic.packets >= previous.packets {
+                Traffic {
+                    bytes: traffic.bytes - previous.bytes,
+                    packets: traffic.packets - previous.packets,
+                }
+            } else {
+                traffic
+            };
+            if delta.packets == 0 && public_key.is_none() {
+                idle.push(pair);
+                continue;
+            }
+
+            let public_key = public_key.or(known_key);
+            add(sample.entry(Talker::Ip(pair.remote.ipv4)).or_default(), &delta);
+            if let Some(public_key) = public_key {
+                add(sample.entry(Talker::Peer(public_key)).or_default(), &delta);
+            }
+            last.insert(pair, (traffic, public_key));
+        }
+        self.last = last;
+
+        self.minutes.push_back(sample);
+        if self.minutes.len() > MINUTES {
+            self.minutes.pop_front();
+        }
+        self.since_rollup += 1;
+        if self.since_rollup == MINUTES {
+            self.hours.push_back(merge(self.minutes.iter()));
+            if self.hours.len() > HOURS {
+                self.hours.pop_front();
+            }
+            self.since_rollup = 0;
+        }
+        idle
+    }
+
+    /// the `count` biggest talkers of every kind for every window
+    pub fn top(&self, count: usize) -> TopReport {
+        let current_hour = self.minutes.iter().rev().take(self.since_rollup);
+        let windows = vec![
+            ("last minute", merge(self.minutes.back().into_iter())),
+            ("last hour", merge(self.minutes.iter())),
+            ("last day", merge(self.hours.iter().chain(current_hour))),
+        ];
+        TopReport {
+            windows: windows
+                .into_iter()
+                .map(|(name, total)| {
+                    let mut talkers = total.into_iter().collect::<Vec<_>>();
+                    talkers.sort_by_key(|(_, traffic)| Reverse(traffic.bytes));
+                    let (ips, peers): (Vec<_>, Vec<_>) = talkers
+                        .into_iter()
+                        .partition(|(talker, _)| matches!(talker, Talker::Ip(_)));
+                    let talkers = ips
+                        .into_iter()
+                        .take(count)
+                        .chain(peers.into_iter().take(count))
+                        .collect();
+                    (name, talkers)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The biggest talkers by bytes for every window
+#[derive(Debug)]
+pub struct TopReport {
+    pub windows: Vec<(&'static str, Vec<(Talker, Traffic)>)>,
+}
+
+impl fmt::Display for TopReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, talkers)) in self.windows.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}:", name)?;
+            if talkers.is_empty() {
+                write!(f, " nothing")?;
+            }
+            for (talker, traffic) in talkers {
+                write!(f, "\n  {}: {} bytes, {} packets", talker, traffic.bytes, traffic.packets)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xdp_module::{Endpoint, EndpointPair, Traffic};
+    use super::{TrafficTracker, Talker};
+
+    fn flow(ip: [u8; 4], bytes: u64, public_key: Option<[u8; 32]>) -> (EndpointPair, Traffic, Option<[u8; 32]>) {
+        let pair = EndpointPair {
+            remote: Endpoint { ipv4: ip, port: [0x26, 0x04] },
+            local: Endpoint { ipv4: [10, 0, 0, 1], port: [0x26, 0x04] },
+        };
+        (pair, Traffic { bytes, packets: bytes / 100 }, public_key)
+    }
+
+    #[test]
+    fn deltas_and_windows() {
+        let mut traffic = TrafficTracker::default();
+        traffic.record(vec![flow([1, 1, 1, 1], 1000, Some([1; 32])), flow([2, 2, 2, 2], 500, None)]);
+        // the peer has closed the connection, its public key is remembered
+        let idle = traffic.record(vec![flow([1, 1, 1, 1], 1200, None), flow([2, 2, 2, 2], 500, None)]);
+        assert_eq!(idle.len(), 1);
+        assert_eq!(idle[0].remote.ipv4, [2, 2, 2, 2]);
+
+        let top = traffic.top(1);
+        let (name, minute) = &top.windows[0];
+        assert_eq!(*name, "last minute");
+        assert_eq!(minute.len(), 2);
+        assert_eq!(minute[0].0, Talker::Ip([1, 1, 1, 1]));
+        assert_eq!(minute[0].1.bytes, 200);
+        assert_eq!(minute[1].0, Talker::Peer([1; 32]));
+
+        let (_, hour) = &top.windows[1];
+        assert_eq!(hour[0].0, Talker::Ip([1, 1, 1, 1]));
+        assert_eq!(hour[0].1.bytes, 1200);
+        let (_, day) = &top.windows[2];
+        assert_eq!(day[0].1.bytes, 1200);
+
+        // an hour later only the day remembers it
+        for _ in 0..60 {
+            traffic.record(Vec::new());
+        }
+        let top = traffic.top(10);
+        assert!(top.windows[0].1.is_empty());
+        assert!(top.windows[1].1.is_empty());
+        assert_eq!(top.windows[2].1.len(), 3);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    sync::{Mutex, mpsc},
+    task,
+};
+
+use crypto::proof_of_work::check_proof_of_work;
+use xdp_module::{BlockingReason, EndpointPair};
+
+use super::{cache::PowCache, maps::MapHandle};
+
+/// What to do with the proof of work when all workers are busy and the queue is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// wait for a free slot, the event handler stops reading events,
+    /// so the kernel perf buffer fills up and loses events
+    Wait,
+    /// let the connection go without verification
+    Accept,
+    /// block the source, it cannot be verified now
+    Block,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(OverflowPolicy::Wait),
+            "accept" => Ok(OverflowPolicy::Accept),
+            "block" => Ok(OverflowPolicy::Block),
+            s => Err(format!("unknown overflow policy: {}, expected wait, accept or block", s)),
+        }
+    }
+}
+
+pub struct Job {
+    pub pair: EndpointPair,
+    pub pow: [u8; 56],
+}
+
+#[derive(Default)]
+pub struct VerifierStats {
+    verified: AtomicU64,
+    overflowed: AtomicU64,
+}
+
+/// Verifies proof of work on a bounded pool of blocking workers,
+/// the event handler only puts jobs into the queue
+pub struct Verifier {
+    jobs: mpsc::Sender<Job>,
+    policy: OverflowPolicy,
+    maps: MapHandle,
+    stats: Arc<VerifierStats>,
+    log: slog::Logger,
+}
+
+impl Verifier {
+    pub fn spawn(
+        workers: usize,
+        queue: usize,
+        policy: OverflowPolicy,
+        target: f64,
+        maps: MapHandle,
+        cache: Arc<Mutex<PowCache>>,
+        log: &slog::Logger,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        let stats = Arc::new(VerifierStats::default());
+        for _ in 0..workers {
+            let rx = rx.clone();
+            let mut maps = maps.clone();
+            let cache = cache.clone();
+            let stats = stats.clone();
+            let log = log.clone();
+            tokio::spawn(async move {
+                while let Some(Job { pair, pow }) = {
+                    let mut rx = rx.lock().await;
+                    rx.recv().await
+                } {
+                    let cached = cache.lock().await.get(&pow, target);
+                    let valid = match cached {
+                        Some(valid) => valid,
+                        None => {
+                            let valid = task::spawn_blocking(move || {
+                                check_proof_of_work(&pow, target).is_ok()
+                            })
+                            .await
+                            .unwrap_or(false);
+                            cache.lock().await.insert(pow, target, valid);
+                            valid
+                        },
+                    };
+                    stats.verified.fetch_add(1, Ordering::Relaxed);
+
+                    let mut public_key = [0; 32];
+                    public_key.clone_from_slice(&pow[..32]);
+                    maps.handshake_finished(public_key, valid).await;
+
+                    if valid {
+                        slog::info!(log, "Proof of work is valid, complexity: {}", target)
+                    } else {
+                        maps.ban(pair, BlockingReason::BadProofOfWork).await
+                    }
+                }
+            });
+        }
+
+        Verifier {
+            jobs: tx,
+            policy,
+            maps,
+            stats,
+            log: log.clone(),
+        }
+    }
+
+    pub async fn submit(&mut self, job: Job) {
+        self.maps.handshake_started().await;
+        let job = match self.jobs.try_send(job) {
+            Ok(()) => return,
+            Err(mpsc::error::TrySendError::Full(job)) => job,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                slog::error!(self.log, "Proof of work workers are gone");
+                return;
+            },
+        };
+
+        self.stats.overflowed.fetch_add(1, Ordering::Relaxed);
+        if let OverflowPolicy::Wait = self.policy {
+            let _ = self.jobs.send(job).await;
+            return;
+        }
+
+        // the job is not verified, but the handshake is finished anyway
+        let mut public_key = [0; 32];
+        public_key.clone_from_slice(&job.pow[..32]);
+        self.maps.handshake_finished(public_key, false).await;
+        match self.policy {
+            OverflowPolicy::Accept => {
+                slog::warn!(self.log, "Proof of work queue is full, accept {:?} without verification", job.pair.remote)
+            },
+            _ => self.maps.ban(job.pair, BlockingReason::Overloaded).await,
+        }
+    }
+
+    pub fn stats(&self) -> Arc<VerifierStats> {
+        self.stats.clone()
+    }
+}
+
+impl fmt::Display for VerifierStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "proof of work workers: verified: {}, queue overflowed: {}",
+            self.verified.load(Ordering::Relaxed),
+            self.overflowed.load(Ordering::Relaxed),
+        )
+    }
+}
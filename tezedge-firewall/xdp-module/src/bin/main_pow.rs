@@ -0,0 +1,8 @@
+#![no_std]
+#![no_main]
+
+/// proof of work is checked by the program itself, the verifier of some kernels rejects it,
+/// in that case userspace falls back to `xdp_module`
+const KERNEL_POW: bool = true;
+
+include!("../firewall.rs");
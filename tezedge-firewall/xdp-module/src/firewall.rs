@@ -0,0 +1,826 @@
+// the XDP program, included by `bin/main.rs` and `bin/main_pow.rs`,
+// they differ only by `KERNEL_POW` constant
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use redbpf_probes::xdp::prelude::*;
+use xdp_module::{
+    Endpoint, EndpointPair, Status, Connection, Event, EventInner, Config, Ipv4Prefix, BanFlags,
+    TokenBucket, FlowQuota, Traffic, PortScan, ProtocolPolicy, Counter, LruHashMap, LpmTrieMap, check_proof_of_work, HANDSHAKES_STARTED,
+    HANDSHAKES_FINISHED, Ipv4Layout, Tunnel, is_icmp_error, is_udp_reply, ethernet_payload, tcp_payload, IPPROTO_ICMP,
+    IPPROTO_TCP, IPPROTO_UDP,
+};
+
+program!(0xFFFFFFFE, "GPL");
+
+type MapVoid = u32;
+
+const ETH_P_IP: u16 = 0x0800;
+
+/// The ipv4 packet behind the vlan tags, or inside the tunnel
+struct Packet {
+    ipv4: *const iphdr,
+    /// where the ipv4 header starts
+    network: usize,
+    /// where the transport header starts
+    transport: usize,
+    /// the packet is a fragment, only the first one has the transport header
+    fragment: bool,
+    headless: bool,
+}
+
+impl Packet {
+    /// walks the vlan tags, not ipv4 is `None`
+    #[inline(always)]
+    fn parse(ctx: &XdpContext) -> Option<Self> {
+        let (protocol, offset) = ethernet(ctx, 0)?;
+        if protocol != ETH_P_IP {
+            return None;
+        }
+        Packet::ipv4(ctx, offset)
+    }
+
+    /// the tunnel of the configured endpoint the packet carries
+    #[inline(always)]
+    fn tunnel(&self, ctx: &XdpContext) -> Option<Tunnel> {
+        let ipv4 = unsafe { &*self.ipv4 };
+        let is_endpoint = || unsafe { tunnels.get(&Ipv4Prefix::host(ipv4.saddr.to_le_bytes())) }.is_some();
+        Tunnel::find(ipv4.protocol, self.fragment, self.transport, is_endpoint, |offset| word(ctx, offset))
+    }
+
+    /// the packet inside the tunnel, the broken or not ipv4 frame is `None`
+    #[inline(always)]
+    fn open(ctx: &XdpContext, tunnel: Tunnel) -> Option<Self> {
+        match tunnel {
+            Tunnel::IpIp(offset) => Packet::ipv4(ctx, offset),
+            Tunnel::Vxlan(offset) => {
+                let (protocol, offset) = ethernet(ctx, offset)?;
+                if protocol != ETH_P_IP {
+                    return None;
+                }
+                Packet::ipv4(ctx, offset)
+            },
+        }
+    }
+
+    #[inline(always)]
+    fn ipv4(ctx: &XdpContext, offset: usize) -> Option<Self> {
+        let ipv4 = unsafe { ctx.ptr_at::<iphdr>(ctx.data_start() + offset) }.ok()?;
+        // the version and the header length share the first byte
+        let version_ihl = unsafe { *(ipv4 as *const u8) };
+        let layout = Ipv4Layout::new(version_ihl, u16::from_be(unsafe { (*ipv4).frag_off }))?;
+        Some(Packet {
+            ipv4,
+            network: offset,
+            transport: offset + layout.header_length,
+            fragment: layout.fragment,
+            headless: layout.headless,
+        })
+    }
+}
+
+#[inline(always)]
+fn ethernet(ctx: &XdpContext, offset: usize) -> Option<(u16, usize)> {
+    ethernet_payload(offset, |offset| word(ctx, offset))
+}
+
+/// four bytes of the frame at the offset
+#[inline(always)]
+fn word(ctx: &XdpContext, offset: usize) -> Option<[u8; 4]> {
+    unsafe { ctx.ptr_at::<[u8; 4]>(ctx.data_start() + offset) }
+        .ok()
+        .map(|word| unsafe { *word })
+}
+
+/// Key of the bucket of the protocol other than tcp
+#[derive(Clone)]
+struct ProtocolSource {
+    ipv4: [u8; 4],
+    protocol: u32,
+}
+
+/// buffer for 256 events, should be enough
+#[map("events")]
+static mut events: PerfMap<Event> = PerfMap::with_max_entries(0x100);
+
+/// limit is 1024 entries
+#[map("blacklist")]
+static mut blacklist: HashMap<[u8; 4], BanFlags> = HashMap::with_max_entries(0x400);
+
+/// trusted networks bypass every check, the value is unused
+#[map("allow_ip")]
+static mut allow_ip: LpmTrieMap<Ipv4Prefix, MapVoid> = LpmTrieMap::with_max_entries(0x400);
+
+/// trusted public keys bypass proof of work and duplicate identity checks
+#[map("allow_pk")]
+static mut allow_pk: HashMap<[u8; 32], MapVoid> = HashMap::with_max_entries(0x400);
+
+/// identities which are never accepted, whatever ip they come from
+#[map("deny_pk")]
+static mut deny_pk: HashMap<[u8; 32], MapVoid> = HashMap::with_max_entries(0x400);
+
+/// banned networks
+#[map("prefix_blacklist")]
+static mut prefix_blacklist: LpmTrieMap<Ipv4Prefix, BanFlags> = LpmTrieMap::with_max_entries(0x400);
+
+/// banned single connections, the other connections from the same ip are fine,
+/// the oldest ban is evicted by newer
+#[map("flow_blacklist")]
+static mut flow_blacklist: LruHashMap<EndpointPair, MapVoid> = LruHashMap::with_max_entries(0x1000);
+
+/// simultaneous 1024 connections maximum, the entry is removed when the connection is closed,
+/// if the peer is gone silently, its entry is evicted by newer
+#[map("peers")]
+static mut peers: LruHashMap<[u8; 32], Endpoint> = LruHashMap::with_max_entries(0x400);
+
+#[map("pending_peers")]
+static mut pending_peers: HashMap<Endpoint, MapVoid> = HashMap::with_max_entries(0x400);
+
+#[map("node")]
+static mut node: HashMap<u16, MapVoid> = HashMap::with_max_entries(1);
+
+/// the entry is removed when the connection is closed, or evicted by newer
+#[map("status")]
+static mut status_map: LruHashMap<EndpointPair, Connection> =
+    LruHashMap::with_max_entries(0x1000);
+
+/// single entry at key 0, userspace writes it before attaching the program
+#[map("config")]
+static mut config_map: HashMap<u32, Config> = HashMap::with_max_entries(1);
+
+/// new connections of every source, the quietest source is evicted by newer
+#[map("syn_buckets")]
+static mut syn_buckets: LruHashMap<Ipv4Prefix, TokenBucket> = LruHashMap::with_max_entries(0x4000);
+
+/// keyed by `Counter`, userspace creates every entry before attaching the program
+#[map("counters")]
+static mut counters: HashMap<u32, u64> = HashMap::with_max_entries(0x40);
+
+/// live connections to the node from every ip, userspace recounts it when sweeping
+#[map("ip_connections")]
+static mut ip_connections: LruHashMap<[u8; 4], u32> = LruHashMap::with_max_entries(0x1000);
+
+/// started and finished handshakes, userspace creates both entries before attaching the program
+/// and it is the only writer, the XDP program only reads them
+#[map("handshakes")]
+static mut handshakes: HashMap<u32, u64> = HashMap::with_max_entries(2);
+
+/// public keys with verified proof of work, they are admitted when too many handshakes are in flight
+#[map("verified")]
+static mut verified: LruHashMap<[u8; 32], MapVoid> = LruHashMap::with_max_entries(0x1000);
+
+/// bandwidth quotas of connections with verified proof of work, removed when the connection is closed
+#[map("flow_quotas")]
+static mut flow_quotas: LruHashMap<EndpointPair, FlowQuota> = LruHashMap::with_max_entries(0x1000);
+
+/// udp and icmp packets of every source, the quietest source is evicted by newer
+#[map("protocol_buckets")]
+static mut protocol_buckets: LruHashMap<ProtocolSource, TokenBucket> = LruHashMap::with_max_entries(0x4000);
+
+/// ports of the host, like the rpc of the node, only allowed sources may connect to them
+#[map("services")]
+static mut services: HashMap<u16, MapVoid> = HashMap::with_max_entries(0x40);
+
+/// sources allowed to connect to the protected ports, the value is unused
+#[map("service_allow")]
+static mut service_allow: LpmTrieMap<Ipv4Prefix, MapVoid> = LpmTrieMap::with_max_entries(0x400);
+
+/// the endpoints whose ip-in-ip and vxlan tunnels are opened, the value is unused
+#[map("tunnels")]
+static mut tunnels: LpmTrieMap<Ipv4Prefix, MapVoid> = LpmTrieMap::with_max_entries(0x100);
+
+/// sources which never appear on the internet and the addresses of the host,
+/// the value is the key of the counter of the category
+#[map("bogons")]
+static mut bogons: LpmTrieMap<Ipv4Prefix, u32> = LpmTrieMap::with_max_entries(0x400);
+
+/// ports every source has tried recently, the quietest source is evicted by newer
+#[map("port_scans")]
+static mut port_scans: LruHashMap<[u8; 4], PortScan> = LruHashMap::with_max_entries(0x4000);
+
+/// traffic of every connection of the node, userspace samples it and removes idle entries
+#[map("traffic")]
+static mut traffic: LruHashMap<EndpointPair, Traffic> = LruHashMap::with_max_entries(0x1000);
+
+/// the entry is shared by every cpu, `+=` would lose increments
+#[inline(always)]
+fn atomic_add(value: &mut u64, delta: u64) {
+    unsafe { &*(value as *mut u64 as *const AtomicU64) }.fetch_add(delta, Ordering::Relaxed);
+}
+
+#[inline(always)]
+fn count(counter: Counter) {
+    if let Some(value) = unsafe { counters.get_mut(&(counter as u32)) } {
+        atomic_add(value, 1);
+    }
+}
+
+#[inline(always)]
+fn config_value<T, F>(f: F) -> T
+where
+    F: FnOnce(&Config) -> T,
+    T: Default,
+{
+    unsafe { config_map.get(&0) }.map(f).unwrap_or_default()
+}
+
+/// the ip holds as many connections as allowed
+#[inline(always)]
+fn ip_is_full(ipv4: &[u8; 4]) -> bool {
+    let max = config_value(|config| config.max_connections_per_ip);
+    max != 0 && unsafe { ip_connections.get(ipv4) }.map(|c| *c >= max).unwrap_or(false)
+}
+
+#[inline(always)]
+fn track_ip_connection(ipv4: &[u8; 4], opened: bool) {
+    match unsafe { ip_connections.get_mut(ipv4) } {
+        Some(connections) if opened => *connections += 1,
+        Some(connections) => *connections = connections.saturating_sub(1),
+        None if opened => unsafe { ip_connections.set(ipv4, &1) },
+        None => (),
+    }
+}
+
+/// too many handshakes wait for userspace to verify proof of work
+#[inline(always)]
+fn handshakes_are_full() -> bool {
+    let max = config_value(|config| config.max_handshakes);
+    if max == 0 {
+        return false;
+    }
+    let started = unsafe { handshakes.get(&HANDSHAKES_STARTED) }.cloned().unwrap_or(0);
+    let finished = unsafe { handshakes.get(&HANDSHAKES_FINISHED) }.cloned().unwrap_or(0);
+    started.saturating_sub(finished) >= max
+}
+
+/// take a token from the bucket of the source, it is refilled with `syn_rate` tokens per second
+#[inline(always)]
+fn admit_syn(ipv4: [u8; 4]) -> bool {
+    let (rate, burst, prefix_length) = match unsafe { config_map.get(&0) } {
+        Some(config) => (config.syn_rate, config.syn_burst, config.syn_prefix_length),
+        None => return true,
+    };
+    if rate == 0 {
+        return true;
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let source = Ipv4Prefix::new(ipv4, prefix_length);
+    match unsafe { syn_buckets.get_mut(&source) } {
+        Some(bucket) => bucket.take(now, rate, burst),
+        None => {
+            let mut bucket = TokenBucket {
+                credit: burst * TokenBucket::TOKEN,
+                last: now,
+            };
+            let admitted = bucket.take(now, rate, burst);
+            unsafe { syn_buckets.set(&source, &bucket) };
+            admitted
+        },
+    }
+}
+
+/// spend the quota of the connection on the packet, returns how many packets the connection
+/// has sent over the quota, zero if this one is within
+#[inline(always)]
+fn spend_quota(pair: &EndpointPair, length: u64) -> u32 {
+    let config = match unsafe { config_map.get(&0) } {
+        Some(config) if config.flow_byte_rate != 0 || config.flow_packet_rate != 0 => config,
+        _ => return 0,
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let (within, dropped) = match unsafe { flow_quotas.get_mut(pair) } {
+        Some(quota) => (quota.spend(now, length, config), quota.dropped),
+        None => {
+            let mut quota = FlowQuota::new(now, config);
+            let within = quota.spend(now, length, config);
+            unsafe { flow_quotas.set(pair, &quota) };
+            (within, quota.dropped)
+        },
+    };
+    if within {
+        0
+    } else {
+        dropped
+    }
+}
+
+/// the source tries to connect the port, returns the number of distinct ports once per window,
+/// when the source reaches the threshold
+#[inline(always)]
+fn detect_scan(ipv4: &[u8; 4], port: u16) -> Option<u32> {
+    let (threshold, window) = match unsafe { config_map.get(&0) } {
+        Some(config) if config.scan_ports != 0 => (config.scan_ports, config.scan_window),
+        _ => return None,
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let ports = match unsafe { port_scans.get_mut(ipv4) } {
+        Some(scan) => scan.hit(port, now, window),
+        None => {
+            let mut scan = PortScan::new(now);
+            let ports = scan.hit(port, now, window);
+            unsafe { port_scans.set(ipv4, &scan) };
+            ports
+        },
+    };
+    if ports == threshold {
+        Some(ports)
+    } else {
+        None
+    }
+}
+
+/// the ban of the source is not limited to the traffic of the node
+#[inline(always)]
+fn banned_for_all(ipv4: [u8; 4]) -> bool {
+    let banned = |ban: &BanFlags| !ban.contains(BanFlags::NODE_ONLY);
+    unsafe { blacklist.get(&ipv4) }.map(banned).unwrap_or(false)
+        || unsafe { prefix_blacklist.get(&Ipv4Prefix::host(ipv4)) }
+            .map(banned)
+            .unwrap_or(false)
+}
+
+/// the policy for udp, icmp and the other protocols, the node uses only tcp,
+/// `header` is the first word of the transport header, the fragment but the first one has none
+#[inline(always)]
+fn not_tcp(ipv4: [u8; 4], protocol: u8, header: Option<[u8; 4]>) -> XdpAction {
+    if unsafe { allow_ip.get(&Ipv4Prefix::host(ipv4)) }.is_some() {
+        return XdpAction::Pass;
+    }
+    let config = match unsafe { config_map.get(&0) } {
+        Some(config) => config,
+        None => return XdpAction::Pass,
+    };
+    // the fragment of tcp without the header, only the ban of the source applies
+    let banned = config.ban_all_protocols || protocol == IPPROTO_TCP;
+    if banned && banned_for_all(ipv4) {
+        count(Counter::BannedNotTcp);
+        return XdpAction::Drop;
+    }
+    apply_policy(ipv4, protocol, header, config)
+}
+
+/// the tunnel packet of the configured endpoint before it is opened, the ban of the endpoint
+/// drops everything inside, the udp policy applies to vxlan
+#[inline(always)]
+fn tunnel_endpoint(ipv4: [u8; 4], protocol: u8, header: Option<[u8; 4]>) -> XdpAction {
+    if unsafe { allow_ip.get(&Ipv4Prefix::host(ipv4)) }.is_some() {
+        return XdpAction::Pass;
+    }
+    if banned_for_all(ipv4) {
+        count(Counter::BannedTunnel);
+        return XdpAction::Drop;
+    }
+    match unsafe { config_map.get(&0) } {
+        Some(config) => apply_policy(ipv4, protocol, header, config),
+        None => XdpAction::Pass,
+    }
+}
+
+/// the policy of udp or icmp, the other protocols pass
+#[inline(always)]
+fn apply_policy(ipv4: [u8; 4], protocol: u8, header: Option<[u8; 4]>, config: &Config) -> XdpAction {
+    let (policy, rate, burst, dropped, limited) = match protocol {
+        IPPROTO_UDP => (
+            config.udp_policy,
+            config.udp_rate,
+            config.udp_burst,
+            Counter::Udp,
+            Counter::UdpRateLimited,
+        ),
+        IPPROTO_ICMP => (
+            config.icmp_policy,
+            config.icmp_rate,
+            config.icmp_burst,
+            Counter::Icmp,
+            Counter::IcmpRateLimited,
+        ),
+        _ => return XdpAction::Pass,
+    };
+    // the answers to the host pass, only unsolicited traffic is dropped or limited
+    let answer = match (protocol, header) {
+        (IPPROTO_UDP, Some(header)) => is_udp_reply(u16::from_be_bytes([header[0], header[1]])),
+        (IPPROTO_ICMP, Some(header)) => is_icmp_error(header[0]),
+        _ => false,
+    };
+    if answer {
+        return XdpAction::Pass;
+    }
+    match policy {
+        ProtocolPolicy::Pass => XdpAction::Pass,
+        ProtocolPolicy::Drop => {
+            count(dropped);
+            XdpAction::Drop
+        },
+        ProtocolPolicy::RateLimit => {
+            let now = unsafe { bpf_ktime_get_ns() };
+            let source = ProtocolSource {
+                ipv4,
+                protocol: protocol as u32,
+            };
+            let admitted = match unsafe { protocol_buckets.get_mut(&source) } {
+                Some(bucket) => bucket.take(now, rate, burst),
+                None => {
+                    let mut bucket = TokenBucket {
+                        credit: burst * TokenBucket::TOKEN,
+                        last: now,
+                    };
+                    let admitted = bucket.take(now, rate, burst);
+                    unsafe { protocol_buckets.set(&source, &bucket) };
+                    admitted
+                },
+            };
+            if admitted {
+                XdpAction::Pass
+            } else {
+                count(limited);
+                XdpAction::Drop
+            }
+        },
+    }
+}
+
+#[inline(always)]
+fn account(pair: &EndpointPair, length: u64) {
+    match unsafe { traffic.get_mut(pair) } {
+        Some(sums) => {
+            atomic_add(&mut sums.bytes, length);
+            atomic_add(&mut sums.packets, 1);
+        },
+        None => {
+            let sums = Traffic {
+                bytes: length,
+                packets: 1,
+            };
+            unsafe { traffic.set(pair, &sums) };
+        },
+    }
+}
+
+#[xdp]
+pub fn firewall(ctx: XdpContext) -> XdpResult {
+    let packet = match Packet::parse(&ctx) {
+        Some(packet) => packet,
+        // TODO: handle ipv6
+        None => return Ok(XdpAction::Pass),
+    };
+    // only the tunnels of the known endpoints are opened, the outer packet is checked first
+    let packet = match packet.tunnel(&ctx) {
+        Some(tunnel) => {
+            let ipv4 = unsafe { &*packet.ipv4 };
+            let header = word(&ctx, packet.transport);
+            if let XdpAction::Drop = tunnel_endpoint(ipv4.saddr.to_le_bytes(), ipv4.protocol, header) {
+                return Ok(XdpAction::Drop);
+            }
+            match Packet::open(&ctx, tunnel) {
+                Some(inner) => inner,
+                // the checks cannot see inside, so it must not reach the host
+                None => {
+                    count(Counter::TunnelNotIpv4);
+                    return Ok(XdpAction::Drop);
+                },
+            }
+        },
+        None => packet,
+    };
+    let ipv4 = unsafe { &*packet.ipv4 };
+    let tcp = if ipv4.protocol == IPPROTO_TCP && !packet.headless {
+        unsafe { ctx.ptr_at::<tcphdr>(ctx.data_start() + packet.transport) }.ok()
+    } else {
+        None
+    };
+    if let Some(tcp) = tcp {
+        let tcp = unsafe { &*tcp };
+
+        let pair = EndpointPair {
+            remote: Endpoint {
+                ipv4: ipv4.saddr.to_le_bytes(),
+                port: tcp.source.to_le_bytes(),
+            },
+            local: Endpoint {
+                ipv4: ipv4.daddr.to_le_bytes(),
+                port: tcp.dest.to_le_bytes(),
+            },
+        };
+
+        // trusted source, let it go
+        if unsafe { allow_ip.get(&Ipv4Prefix::host(pair.remote.ipv4)) }.is_some() {
+            return Ok(XdpAction::Pass);
+        }
+
+        // check if already blacklisted, the ban might be limited to the traffic of the node
+        let mut banned = false;
+        if let Some(ban) = unsafe { blacklist.get(&pair.remote.ipv4) } {
+            if !ban.contains(BanFlags::NODE_ONLY) {
+                return Ok(XdpAction::Drop);
+            }
+            banned = true;
+        }
+        if let Some(ban) = unsafe { prefix_blacklist.get(&Ipv4Prefix::host(pair.remote.ipv4)) } {
+            if !ban.contains(BanFlags::NODE_ONLY) {
+                return Ok(XdpAction::Drop);
+            }
+            banned = true;
+        }
+
+        // any port of the host counts, scanners come back to attack the node
+        if tcp.syn() != 0 && tcp.ack() == 0 {
+            let port = u16::from_be_bytes(pair.local.port.clone());
+            if let Some(ports) = detect_scan(&pair.remote.ipv4, port) {
+                let event = Event {
+                    pair: pair.clone(),
+                    event: EventInner::PortScan(ports),
+                };
+                unsafe { events.insert(&ctx, &MapData::new(event)) };
+            }
+        }
+
+        // protected service, only allowed sources
+        let local_port = u16::from_be_bytes(pair.local.port.clone());
+        if unsafe { services.get(&local_port) }.is_some() {
+            if unsafe { service_allow.get(&Ipv4Prefix::host(pair.remote.ipv4)) }.is_none() {
+                count(Counter::ServiceDenied);
+                return Ok(XdpAction::Drop);
+            }
+            return Ok(XdpAction::Pass);
+        }
+
+        // this code might look obscure
+        // it should be:
+        //      `let incoming = unsafe { node.get(&port) }.is_some();`
+        //      `let outgoing = unsafe { pending_peers.get(&pair.remote) }.is_some();`
+        //      `let ours = incoming || outgoing`
+        // but actually `HashMap::get` returns pointer and
+        // llvm optimize `incoming` and `outgoing` to be not boolean, but pointers
+        // and `pointer || pointer` is forbidden operation,
+        // let's compare pointer with 3 to force it to be boolean
+
+        // check if ours message
+        let incoming = unsafe {
+            let port = u16::from_be_bytes(pair.local.port.clone());
+            bpf_map_lookup_elem(
+                &mut node as *mut _ as *mut c_void,
+                &port as *const _ as *const c_void,
+            ) as usize
+        } > 3;
+        let outgoing = unsafe {
+            bpf_map_lookup_elem(
+                &mut pending_peers as *mut _ as *mut c_void,
+                &pair.remote as *const _ as *const c_void,
+            ) as usize
+        } > 3;
+        let ours = incoming || outgoing;
+        if !ours {
+            return Ok(XdpAction::Pass);
+        }
+        if banned {
+            return Ok(XdpAction::Drop);
+        }
+        // the node's traffic is never fragmented, the rest of the message would evade the checks
+        if packet.fragment {
+            count(Counter::Fragmented);
+            return Ok(XdpAction::Drop);
+        }
+        // bogon or spoofed source, the node must never answer it
+        if incoming {
+            if let Some(category) = unsafe { bogons.get(&Ipv4Prefix::host(pair.remote.ipv4)) } {
+                if let Some(value) = unsafe { counters.get_mut(category) } {
+                    atomic_add(value, 1);
+                }
+                return Ok(XdpAction::Drop);
+            }
+        }
+
+        // new connection to the node
+        if incoming && tcp.syn() != 0 && tcp.ack() == 0 && !admit_syn(pair.remote.ipv4) {
+            count(Counter::SynRateLimited);
+            return Ok(XdpAction::Drop);
+        }
+        if unsafe { flow_blacklist.get(&pair) }.is_some() {
+            return Ok(XdpAction::Drop);
+        }
+        account(&pair, (ctx.data_end() - ctx.data_start()) as u64);
+
+        // the connection is closing, forget it, so the peer can connect again
+        if tcp.fin() != 0 || tcp.rst() != 0 {
+            if let Some(connection) = unsafe { status_map.get(&pair) } {
+                if incoming && !connection.status.contains(Status::BLOCKED) {
+                    track_ip_connection(&pair.remote.ipv4, false);
+                }
+                let public_key = connection.public_key.clone();
+                let same_connection = unsafe { peers.get(&public_key) }
+                    .map(|endpoint| endpoint.eq(&pair.remote))
+                    .unwrap_or(false);
+                if same_connection {
+                    unsafe { peers.delete(&public_key) };
+                }
+                unsafe {
+                    status_map.delete(&pair);
+                    flow_quotas.delete(&pair);
+                }
+            }
+            return Ok(XdpAction::Pass);
+        }
+
+        // check if packet has payload, the ethernet padding is not the payload
+        let (payload_offset, payload_length) = tcp_payload(
+            packet.network,
+            u16::from_be(ipv4.tot_len),
+            packet.transport,
+            tcp.doff() as u8,
+        );
+        let has_payload = payload_length != 0;
+        if !has_payload {
+            // the connection is established, remember when, userspace kicks it
+            // if it sends nothing for too long
+            let established = tcp.ack() != 0 && tcp.syn() == 0;
+            if incoming && established && unsafe { status_map.get(&pair) }.is_none() {
+                let connection = Connection {
+                    status: Status::empty(),
+                    public_key: [0; 32],
+                    since: unsafe { bpf_ktime_get_ns() },
+                };
+                unsafe { status_map.set(&pair, &connection) };
+            }
+            return Ok(XdpAction::Pass);
+        }
+
+        // check if it is the first payload of the connection
+        let mut connection = unsafe { status_map.get(&pair) }
+            .cloned()
+            .unwrap_or(Connection {
+                status: Status::empty(),
+                public_key: [0; 32],
+                since: unsafe { bpf_ktime_get_ns() },
+            });
+        // retransmission of the dropped payload waits until userspace decides
+        if connection.status.contains(Status::BLOCKED) {
+            return Ok(XdpAction::Drop);
+        }
+        // the peer is verified, but it can still flood the node, unless it is trusted
+        if connection.status.contains(Status::POW_SENT) {
+            if unsafe { allow_pk.get(&connection.public_key) }.is_some() {
+                return Ok(XdpAction::Pass);
+            }
+            return match spend_quota(&pair, (ctx.data_end() - ctx.data_start()) as u64) {
+                0 => Ok(XdpAction::Pass),
+                dropped => {
+                    count(Counter::QuotaExceeded);
+                    let strikes = config_value(|config| config.quota_strikes);
+                    if dropped == strikes {
+                        let event = Event {
+                            pair: pair.clone(),
+                            event: EventInner::QuotaExceeded(dropped),
+                        };
+                        unsafe { events.insert(&ctx, &MapData::new(event)) };
+                    }
+                    Ok(XdpAction::Drop)
+                },
+            };
+        }
+        let mut status = Status::POW_SENT;
+
+        // one ip should not hold too many connections, the retransmission is checked again,
+        // some connection of the ip might be closed meanwhile
+        if incoming && ip_is_full(&pair.remote.ipv4) {
+            count(Counter::ConnectionsPerIp);
+            return Ok(XdpAction::Drop);
+        }
+
+        // initialize event structure
+        let mut event = Event {
+            pair: pair.clone(),
+            event: EventInner::ReceivedPow([0; 56]),
+        };
+        let mut report = true;
+
+        // check the chunk framing before anything else, port scanners and http probes
+        // should not reach proof of work check in userspace
+        let framing = match unsafe { ctx.ptr_at::<[u8; 2]>(ctx.data_start() + payload_offset) } {
+            Ok(length) => {
+                let chunk_length = u16::from_be_bytes(unsafe { *length });
+                EventInner::check_chunk_framing(chunk_length, payload_length)
+            },
+            Err(_) => Some(EventInner::NotTezosTraffic {
+                chunk_length: 0,
+                payload_length,
+            }),
+        };
+
+        if let Some(not_tezos) = framing {
+            event.event = not_tezos;
+            status.insert(Status::BLOCKED);
+        } else if let Ok(data) = unsafe { ctx.ptr_at::<[u8; 60]>(ctx.data_start() + payload_offset) } {
+            // first payload is big enough to read proof of work
+            let pow_data = &unsafe { &*data }[4..];
+            let mut public_key = [0; 32];
+            public_key.clone_from_slice(&pow_data[..32]);
+            connection.public_key = public_key.clone();
+            let trusted = unsafe { allow_pk.get(&public_key) }.is_some();
+            // in private mode only trusted peers may connect to the node
+            let private = incoming
+                && unsafe { config_map.get(&0) }
+                    .map(|config| config.private_mode)
+                    .unwrap_or(false);
+            let denied = !trusted && unsafe { deny_pk.get(&public_key) }.is_some();
+            if denied {
+                event.event = EventInner::DeniedPeer(public_key);
+                status.insert(Status::BLOCKED);
+            } else if private && !trusted {
+                event.event = EventInner::NotTrusted(public_key);
+                status.insert(Status::BLOCKED);
+            } else {
+                // userspace replaces the entry of the dead connection with this one
+                let already_connected = if trusted {
+                    None
+                } else {
+                    unsafe { peers.get(&public_key) }
+                        .filter(|endpoint| **endpoint != pair.remote)
+                        .cloned()
+                };
+                match already_connected {
+                    // trusted peer, no need to check anything
+                    None if trusted => report = false,
+                    // have no such peer connected, let's check its proof of work
+                    None => {
+                        let mut pow = [0; 56];
+                        pow.clone_from_slice(pow_data);
+                        let verdict = if KERNEL_POW {
+                            unsafe { config_map.get(&0) }
+                                .map(|config| check_proof_of_work(&pow, &config.pow_target))
+                        } else {
+                            None
+                        };
+                        match verdict {
+                            // verified here, userspace need not know about it
+                            Some(true) => {
+                                report = false;
+                                unsafe {
+                                    peers.set(&public_key, &pair.remote);
+                                    verified.set(&public_key, &0);
+                                }
+                            },
+                            Some(false) => {
+                                event.event = EventInner::BadProofOfWork(pow);
+                                status.insert(Status::BLOCKED);
+                            },
+                            // verified peers are admitted even if userspace is busy,
+                            // the retransmission is checked again
+                            None if incoming
+                                && handshakes_are_full()
+                                && unsafe { verified.get(&public_key) }.is_none() =>
+                            {
+                                count(Counter::HandshakesInFlight);
+                                return Ok(XdpAction::Drop);
+                            },
+                            // let userspace check it
+                            None => {
+                                event.event = EventInner::ReceivedPow(pow);
+                                unsafe { peers.set(&public_key, &pair.remote) };
+                            },
+                        }
+                    },
+                    // have such peer connected, let's block him
+                    Some(endpoint) => {
+                        event.event = EventInner::BlockedAlreadyConnected {
+                            already_connected: endpoint,
+                            try_connect: pair.remote.clone(),
+                            public_key,
+                        };
+                        status.insert(Status::BLOCKED);
+                    },
+                }
+            }
+        } else {
+            // the frame is shorter than the ipv4 header says, the framing check rejects short payloads
+            event.event = EventInner::NotEnoughBytesForPow;
+            status.insert(Status::BLOCKED);
+        }
+
+        connection.status = status;
+        if incoming && !status.contains(Status::BLOCKED) {
+            track_ip_connection(&pair.remote.ipv4, true);
+        }
+        unsafe {
+            status_map.set(&pair, &connection);
+            if report {
+                events.insert(&ctx, &MapData::new(event));
+            }
+        }
+
+        if status.contains(Status::BLOCKED) {
+            Ok(XdpAction::Drop)
+        } else {
+            Ok(XdpAction::Pass)
+        }
+    } else {
+        // not TCP, or the fragment without the header
+        let header = if packet.headless {
+            None
+        } else {
+            word(&ctx, packet.transport)
+        };
+        Ok(not_tcp(ipv4.saddr.to_le_bytes(), ipv4.protocol, header))
+    }
+}
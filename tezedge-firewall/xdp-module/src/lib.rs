@@ -1,13 +1,21 @@
 #![no_std]
 
-#[derive(Debug, Clone)]
+mod pow;
+pub use self::pow::{POW_DATA_LENGTH, blake2b_256, check_proof_of_work};
+
+#[cfg(feature = "probes")]
+mod maps;
+#[cfg(feature = "probes")]
+pub use self::maps::{LruHashMap, LpmTrieMap};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EndpointPair {
     pub remote: Endpoint,
     pub local: Endpoint,
 }
 
 // TODO: ipv6
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Endpoint {
     pub ipv4: [u8; 4],
     pub port: [u8; 2],
@@ -27,7 +35,544 @@ pub enum EventInner {
     BlockedAlreadyConnected {
         already_connected: Endpoint,
         try_connect: Endpoint,
+        public_key: [u8; 32],
     },
+    NotTezosTraffic {
+        chunk_length: u16,
+        payload_length: u16,
+    },
+    BadProofOfWork([u8; 56]),
+    /// private mode, the public key is not trusted
+    NotTrusted([u8; 32]),
+    /// the public key is in the denylist
+    DeniedPeer([u8; 32]),
+    /// the peer with verified proof of work keeps sending over its quota,
+    /// the number of packets dropped so far
+    QuotaExceeded(u32),
+    /// the source has tried too many distinct ports of the host, the number of ports
+    PortScan(u32),
+}
+
+/// settings written by userspace
+#[derive(Clone)]
+pub struct Config {
+    /// big endian, see `check_proof_of_work`
+    pub pow_target: [u8; 32],
+    /// only trusted peers may connect to the node
+    pub private_mode: bool,
+    /// new connections per second from the source, zero disables the limit
+    pub syn_rate: u64,
+    /// how many new connections the source can open at once
+    pub syn_burst: u64,
+    /// the source is the network of this prefix length
+    pub syn_prefix_length: u32,
+    /// live connections to the node from a single ip, zero disables the limit
+    pub max_connections_per_ip: u32,
+    /// handshakes waiting for proof of work verification, zero disables the limit,
+    /// verified peers are admitted anyway
+    pub max_handshakes: u64,
+    /// bytes per second a connection with verified proof of work can send, zero disables the quota
+    pub flow_byte_rate: u64,
+    pub flow_byte_burst: u64,
+    /// packets per second a connection with verified proof of work can send, zero disables the quota
+    pub flow_packet_rate: u64,
+    pub flow_packet_burst: u64,
+    /// how many packets over the quota are dropped before the connection is reported,
+    /// zero never reports it
+    pub quota_strikes: u32,
+    /// distinct ports of the host a source can try during the window, zero disables the detection
+    pub scan_ports: u32,
+    /// nanoseconds
+    pub scan_window: u64,
+    /// what to do with udp, the rate and burst are packets of a single source ip
+    pub udp_policy: ProtocolPolicy,
+    pub udp_rate: u64,
+    pub udp_burst: u64,
+    /// what to do with icmp, the rate and burst are packets of a single source ip
+    pub icmp_policy: ProtocolPolicy,
+    pub icmp_rate: u64,
+    pub icmp_burst: u64,
+    /// the blacklist drops every protocol of the banned source, not only tcp
+    pub ban_all_protocols: bool,
+}
+
+/// What the XDP program does with the traffic of the protocol other than tcp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ProtocolPolicy {
+    Pass,
+    Drop,
+    /// the token bucket of the source
+    RateLimit,
+}
+
+impl core::str::FromStr for ProtocolPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(ProtocolPolicy::Pass),
+            "drop" => Ok(ProtocolPolicy::Drop),
+            "rate-limit" => Ok(ProtocolPolicy::RateLimit),
+            _ => Err("unknown policy, expected pass, drop or rate-limit"),
+        }
+    }
+}
+
+/// icmp errors answer the traffic of the host, the path mtu discovery needs
+/// the destination unreachable with the code fragmentation needed, so they are never dropped
+#[inline(always)]
+pub fn is_icmp_error(icmp_type: u8) -> bool {
+    // destination unreachable, time exceeded and parameter problem
+    matches!(icmp_type, 3 | 11 | 12)
+}
+
+/// udp from dns or ntp servers answers the queries of the host, so it is never dropped,
+/// the fragments of the answer but the first one have no port and are not recognized
+#[inline(always)]
+pub fn is_udp_reply(source_port: u16) -> bool {
+    matches!(source_port, 53 | 123)
+}
+
+pub const ETH_P_8021Q: u16 = 0x8100;
+pub const ETH_P_8021AD: u16 = 0x88a8;
+pub const ETHERNET_HEADER_LENGTH: usize = 14;
+pub const IP_MF: u16 = 0x2000;
+pub const IP_OFFSET: u16 = 0x1fff;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_IPIP: u8 = 4;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const VXLAN_PORT: u16 = 4789;
+/// udp header and vxlan header
+pub const VXLAN_HEADERS_LENGTH: usize = 8 + 8;
+
+/// returns the ethertype and where the network header starts behind the ethernet header
+/// at `offset`, skips two vlan tags at most, `word` reads four bytes of the frame at the offset
+#[inline(always)]
+pub fn ethernet_payload<F>(offset: usize, word: F) -> Option<(u16, usize)>
+where
+    F: Fn(usize) -> Option<[u8; 4]>,
+{
+    // the ethertype is the last two bytes of the header
+    let ethertype = word(offset + ETHERNET_HEADER_LENGTH - 4)?;
+    let protocol = u16::from_be_bytes([ethertype[2], ethertype[3]]);
+    // 802.1Q, or 802.1ad, the outer tag of QinQ
+    let (protocol, offset) = skip_vlan(protocol, offset + ETHERNET_HEADER_LENGTH, &word)?;
+    // the inner tag of QinQ
+    skip_vlan(protocol, offset, &word)
+}
+
+#[inline(always)]
+fn skip_vlan<F>(protocol: u16, offset: usize, word: &F) -> Option<(u16, usize)>
+where
+    F: Fn(usize) -> Option<[u8; 4]>,
+{
+    if protocol != ETH_P_8021Q && protocol != ETH_P_8021AD {
+        return Some((protocol, offset));
+    }
+    // the tag control information and the ethertype of the payload
+    let tag = word(offset)?;
+    Some((u16::from_be_bytes([tag[2], tag[3]]), offset + 4))
+}
+
+/// What the parser needs from the ipv4 header
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ipv4Layout {
+    pub header_length: usize,
+    /// the packet is a fragment, only the first one has the transport header
+    pub fragment: bool,
+    /// the fragment without the transport header
+    pub headless: bool,
+}
+
+impl Ipv4Layout {
+    /// `version_ihl` is the first byte of the header, `fragment_offset` is in host byte order,
+    /// not ipv4 or the header shorter than 20 bytes is `None`
+    #[inline(always)]
+    pub fn new(version_ihl: u8, fragment_offset: u16) -> Option<Self> {
+        let header_length = ((version_ihl & 0xf) as usize) * 4;
+        if version_ihl >> 4 != 4 || header_length < 20 {
+            return None;
+        }
+        Some(Ipv4Layout {
+            header_length,
+            fragment: fragment_offset & (IP_MF | IP_OFFSET) != 0,
+            headless: fragment_offset & IP_OFFSET != 0,
+        })
+    }
+}
+
+/// where the tcp payload of the ipv4 packet starts and its length, the packet starts at `network`
+/// and is `total_length` bytes, the ethernet padding beyond it is not the payload
+#[inline(always)]
+pub fn tcp_payload(network: usize, total_length: u16, tcp_offset: usize, data_offset: u8) -> (usize, u16) {
+    let headers_end = tcp_offset + (data_offset as usize) * 4;
+    let length = (network + total_length as usize).saturating_sub(headers_end) as u16;
+    (headers_end, length)
+}
+
+/// Where the tunnelled frame starts
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tunnel {
+    /// the ipv4 header
+    IpIp(usize),
+    /// the ethernet header
+    Vxlan(usize),
+}
+
+impl Tunnel {
+    /// the ip-in-ip or vxlan tunnel the ipv4 packet carries, the fragment carries none,
+    /// only the tunnels of the known endpoints are opened, `is_endpoint` looks the source up,
+    /// `word` reads four bytes of the frame at the offset
+    #[inline(always)]
+    pub fn find<E, F>(protocol: u8, fragment: bool, transport: usize, is_endpoint: E, word: F) -> Option<Self>
+    where
+        E: FnOnce() -> bool,
+        F: Fn(usize) -> Option<[u8; 4]>,
+    {
+        if fragment {
+            return None;
+        }
+        let tunnel = match protocol {
+            IPPROTO_IPIP => Tunnel::IpIp(transport),
+            IPPROTO_UDP => {
+                // the source port and the destination port
+                let ports = word(transport)?;
+                if u16::from_be_bytes([ports[2], ports[3]]) != VXLAN_PORT {
+                    return None;
+                }
+                Tunnel::Vxlan(transport + VXLAN_HEADERS_LENGTH)
+            },
+            _ => return None,
+        };
+        if is_endpoint() {
+            Some(tunnel)
+        } else {
+            None
+        }
+    }
+}
+
+/// keys of the `handshakes` map, userspace counts both when it receives the proof of work
+/// and when it has verified it, so the events lost in the perf buffer are never counted
+pub const HANDSHAKES_STARTED: u32 = 0;
+pub const HANDSHAKES_FINISHED: u32 = 1;
+
+/// the largest rate and burst of connections or packets, the arithmetic of the bucket must not overflow
+pub const PACKET_RATE_MAX: u64 = 1_000_000;
+
+/// the largest byte rate and burst, a gigabyte per second
+pub const FLOW_BYTE_RATE_MAX: u64 = 1 << 30;
+
+/// the smallest byte burst, the bucket must fit the biggest packet
+pub const FLOW_BYTE_BURST_MIN: u64 = 1 << 16;
+
+/// the ports are hashed into this many bits, the source cannot be seen trying more ports
+pub const PORT_SCAN_MAX: u32 = 0x100;
+
+/// Distinct ports of the host a source has tried since the window has started,
+/// ports colliding in the bitmap are counted once, so the count is a lower bound
+#[derive(Clone)]
+pub struct PortScan {
+    /// nanoseconds since boot
+    pub since: u64,
+    pub ports: [u64; 4],
+    pub count: u32,
+}
+
+impl PortScan {
+    #[inline(always)]
+    pub fn new(now: u64) -> Self {
+        PortScan {
+            since: now,
+            ports: [0; 4],
+            count: 0,
+        }
+    }
+
+    /// remember the port, the window starts over if it is over, returns the count
+    #[inline(always)]
+    pub fn hit(&mut self, port: u16, now: u64, window: u64) -> u32 {
+        if now.saturating_sub(self.since) > window {
+            *self = PortScan::new(now);
+        }
+        // fibonacci hashing, the top byte of the product depends on every bit of the port,
+        // so the ports 256 apart do not collide like their low bytes do
+        let bit = ((port as u32).wrapping_mul(0x9e37_79b9) >> 24) as usize;
+        let mask = 1u64 << (bit & 0x3f);
+        let word = &mut self.ports[(bit >> 6) & 0x3];
+        if *word & mask == 0 {
+            *word |= mask;
+            self.count += 1;
+        }
+        self.count
+    }
+}
+
+/// Token bucket, the credit is in billionths of the token, so the refill needs no division
+#[derive(Clone)]
+pub struct TokenBucket {
+    pub credit: u64,
+    /// nanoseconds since boot
+    pub last: u64,
+}
+
+impl TokenBucket {
+    pub const TOKEN: u64 = 1_000_000_000;
+
+    /// refill the bucket and take a token if there is one
+    #[inline(always)]
+    pub fn take(&mut self, now: u64, rate: u64, burst: u64) -> bool {
+        self.take_many(now, rate, burst, 1)
+    }
+
+    /// refill the bucket and take `tokens` if there are enough
+    #[inline(always)]
+    pub fn take_many(&mut self, now: u64, rate: u64, burst: u64, tokens: u64) -> bool {
+        self.refill(now, rate, burst);
+        if self.holds(tokens) {
+            self.credit -= tokens * Self::TOKEN;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// add the credit earned since the last refill
+    #[inline(always)]
+    pub fn refill(&mut self, now: u64, rate: u64, burst: u64) {
+        // the bucket is full after `burst / rate` seconds anyway, the cap prevents overflow,
+        // the bucket without the rate is never refilled
+        let full = (burst * Self::TOKEN).checked_div(rate).unwrap_or(0);
+        let elapsed = now.saturating_sub(self.last).min(full);
+        self.last = now;
+        self.credit = (self.credit + elapsed * rate).min(burst * Self::TOKEN);
+    }
+
+    #[inline(always)]
+    pub fn holds(&self, tokens: u64) -> bool {
+        self.credit >= tokens * Self::TOKEN
+    }
+}
+
+/// Bytes and packets the remote endpoint of the connection has sent, they only grow
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traffic {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+/// Bandwidth quota of the connection with verified proof of work
+#[derive(Clone)]
+pub struct FlowQuota {
+    pub bytes: TokenBucket,
+    pub packets: TokenBucket,
+    /// packets dropped over the quota
+    pub dropped: u32,
+}
+
+impl FlowQuota {
+    /// both buckets are full
+    #[inline(always)]
+    pub fn new(now: u64, config: &Config) -> Self {
+        FlowQuota {
+            bytes: TokenBucket {
+                credit: config.flow_byte_burst * TokenBucket::TOKEN,
+                last: now,
+            },
+            packets: TokenBucket {
+                credit: config.flow_packet_burst * TokenBucket::TOKEN,
+                last: now,
+            },
+            dropped: 0,
+        }
+    }
+
+    /// spend the quota on the packet of `length` bytes, returns false and counts the packet
+    /// as dropped if the quota is exceeded, the dropped packet spends nothing
+    #[inline(always)]
+    pub fn spend(&mut self, now: u64, length: u64, config: &Config) -> bool {
+        let packets = config.flow_packet_rate != 0;
+        let bytes = config.flow_byte_rate != 0;
+        if packets {
+            self.packets
+                .refill(now, config.flow_packet_rate, config.flow_packet_burst);
+        }
+        if bytes {
+            self.bytes
+                .refill(now, config.flow_byte_rate, config.flow_byte_burst);
+        }
+        if (packets && !self.packets.holds(1)) || (bytes && !self.bytes.holds(length)) {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        if packets {
+            self.packets.credit -= TokenBucket::TOKEN;
+        }
+        if bytes {
+            self.bytes.credit -= length * TokenBucket::TOKEN;
+        }
+        true
+    }
+}
+
+/// Keys of the `counters` map
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum Counter {
+    SynRateLimited,
+    ConnectionsPerIp,
+    HandshakesInFlight,
+    QuotaExceeded,
+    Loopback,
+    LinkLocal,
+    Multicast,
+    Reserved,
+    Spoofed,
+    ServiceDenied,
+    Udp,
+    UdpRateLimited,
+    Icmp,
+    IcmpRateLimited,
+    BannedNotTcp,
+    Fragmented,
+    TunnelNotIpv4,
+    BannedTunnel,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 18] = [
+        Counter::SynRateLimited,
+        Counter::ConnectionsPerIp,
+        Counter::HandshakesInFlight,
+        Counter::QuotaExceeded,
+        Counter::Loopback,
+        Counter::LinkLocal,
+        Counter::Multicast,
+        Counter::Reserved,
+        Counter::Spoofed,
+        Counter::ServiceDenied,
+        Counter::Udp,
+        Counter::UdpRateLimited,
+        Counter::Icmp,
+        Counter::IcmpRateLimited,
+        Counter::BannedNotTcp,
+        Counter::Fragmented,
+        Counter::TunnelNotIpv4,
+        Counter::BannedTunnel,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::SynRateLimited => "syn rate limited",
+            Counter::ConnectionsPerIp => "too many connections from ip",
+            Counter::HandshakesInFlight => "too many handshakes",
+            Counter::QuotaExceeded => "over bandwidth quota",
+            Counter::Loopback => "loopback source",
+            Counter::LinkLocal => "link-local source",
+            Counter::Multicast => "multicast source",
+            Counter::Reserved => "reserved source",
+            Counter::Spoofed => "own address as source",
+            Counter::ServiceDenied => "not allowed to protected service",
+            Counter::Udp => "udp",
+            Counter::UdpRateLimited => "udp rate limited",
+            Counter::Icmp => "icmp",
+            Counter::IcmpRateLimited => "icmp rate limited",
+            Counter::BannedNotTcp => "banned source, not tcp",
+            Counter::Fragmented => "fragmented tcp to the node",
+            Counter::TunnelNotIpv4 => "tunnelled, not ipv4",
+            Counter::BannedTunnel => "banned tunnel endpoint",
+        }
+    }
+}
+
+/// Key of the longest prefix match trie, the layout is dictated by the kernel
+#[repr(C)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Ipv4Prefix {
+    pub prefix_length: u32,
+    pub ipv4: [u8; 4],
+}
+
+impl Ipv4Prefix {
+    /// the key to look up a single address
+    #[inline(always)]
+    pub fn host(ipv4: [u8; 4]) -> Self {
+        Ipv4Prefix {
+            prefix_length: 32,
+            ipv4,
+        }
+    }
+
+    /// the bits beyond the prefix length are cleared
+    #[inline(always)]
+    pub fn new(ipv4: [u8; 4], prefix_length: u32) -> Self {
+        let prefix_length = prefix_length.min(32);
+        let mask = u32::MAX.checked_shl(32 - prefix_length).unwrap_or(0);
+        Ipv4Prefix {
+            prefix_length,
+            ipv4: (u32::from_be_bytes(ipv4) & mask).to_be_bytes(),
+        }
+    }
+
+    pub fn contains(&self, ipv4: [u8; 4]) -> bool {
+        Ipv4Prefix::new(ipv4, self.prefix_length).ipv4 == self.ipv4
+    }
+}
+
+/// `ConnectionMessage` is port, public key, proof of work stamp, nonce
+/// and the network version (4 bytes of the chain name length, the name might be empty,
+/// and two 2 bytes versions)
+pub const CONNECTION_MESSAGE_MIN_LENGTH: u16 = 2 + 32 + 24 + 24 + 4 + 2 + 2;
+
+/// the real message is about 100 bytes, anything bigger than this is not a `ConnectionMessage`
+pub const CONNECTION_MESSAGE_MAX_LENGTH: u16 = 0x400;
+
+/// the chunk length, the port, the public key and the proof of work stamp,
+/// the first segment must carry them, the proof of work check reads them at once
+pub const FIRST_SEGMENT_MIN_LENGTH: u16 = 2 + 2 + 32 + 24;
+
+impl EventInner {
+    /// the identity the peer has presented, if the event has it
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        let mut public_key = [0; 32];
+        match self {
+            EventInner::ReceivedPow(b) | EventInner::BadProofOfWork(b) => {
+                public_key.clone_from_slice(&b[..32]);
+                Some(public_key)
+            },
+            EventInner::BlockedAlreadyConnected { public_key, .. } => Some(*public_key),
+            EventInner::NotTrusted(public_key) | EventInner::DeniedPeer(public_key) => Some(*public_key),
+            EventInner::NotEnoughBytesForPow
+            | EventInner::NotTezosTraffic { .. }
+            | EventInner::QuotaExceeded(_)
+            | EventInner::PortScan(_) => None,
+        }
+    }
+
+    /// check the first chunk of the connection, `chunk_length` is the first two bytes of the payload,
+    /// the first packet might carry only the beginning of the chunk, but the chunk should not be
+    /// followed by anything, because the remote peer should wait our `ConnectionMessage`,
+    /// the first packet shorter than `FIRST_SEGMENT_MIN_LENGTH` is still rejected,
+    /// the real peer never splits the message so early
+    #[inline(always)]
+    pub fn check_chunk_framing(chunk_length: u16, payload_length: u16) -> Option<Self> {
+        let plausible =
+            (CONNECTION_MESSAGE_MIN_LENGTH..=CONNECTION_MESSAGE_MAX_LENGTH).contains(&chunk_length);
+        // chunk length does not include 2 bytes of the length itself
+        let consistent = (chunk_length as u32) + 2 >= payload_length as u32;
+        if plausible && consistent && payload_length < FIRST_SEGMENT_MIN_LENGTH {
+            Some(EventInner::NotEnoughBytesForPow)
+        } else if plausible && consistent {
+            None
+        } else {
+            Some(EventInner::NotTezosTraffic {
+                chunk_length,
+                payload_length,
+            })
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -37,6 +582,20 @@ pub enum BlockingReason {
     BadProofOfWork,
     AlreadyConnected,
     EventFromTezedge,
+    NotTezosTraffic,
+    Overloaded,
+    IdentityChurn,
+    HandshakeTimeout,
+    QuotaExceeded,
+    PortScan,
+}
+
+bitflags::bitflags! {
+    /// The value of the ip and prefix blacklists
+    pub struct BanFlags: u32 {
+        /// drop only the traffic of the node, the other services of the host are reachable
+        const NODE_ONLY = 0b00000000_00000000_00000000_00000001;
+    }
 }
 
 bitflags::bitflags! {
@@ -46,12 +605,24 @@ bitflags::bitflags! {
     }
 }
 
+/// The state of a watched connection
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub status: Status,
+    /// the public key from the `ConnectionMessage`, the XDP program removes it
+    /// from `peers` when the connection is closed
+    pub public_key: [u8; 32],
+    /// nanoseconds since boot when the XDP program has seen the connection the first time,
+    /// the connection without `POW_SENT` is silent since then
+    pub since: u64,
+}
+
 mod implementations {
     use core::{
         fmt,
         convert::{TryFrom, TryInto},
     };
-    use super::{EndpointPair, Endpoint, EventInner};
+    use super::{EndpointPair, Endpoint, EventInner, Ipv4Prefix};
 
     impl From<EndpointPair> for [u8; 12] {
         fn from(v: EndpointPair) -> Self {
@@ -79,6 +650,13 @@ mod implementations {
         }
     }
 
+    impl fmt::Debug for Ipv4Prefix {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let ip = self.ipv4;
+            write!(f, "{}.{}.{}.{}/{}", ip[0], ip[1], ip[2], ip[3], self.prefix_length)
+        }
+    }
+
     impl From<Endpoint> for [u8; 6] {
         fn from(v: Endpoint) -> Self {
             let mut r = [0; 6];
@@ -109,11 +687,36 @@ mod implementations {
                 &EventInner::BlockedAlreadyConnected {
                     ref already_connected,
                     ref try_connect,
+                    ref public_key,
                 } => f
                     .debug_struct("BlockedAlreadyConnected")
                     .field("already_connected", already_connected)
                     .field("try_connect", try_connect)
+                    .field("public_key", public_key)
+                    .finish(),
+                &EventInner::NotTezosTraffic {
+                    ref chunk_length,
+                    ref payload_length,
+                } => f
+                    .debug_struct("NotTezosTraffic")
+                    .field("chunk_length", chunk_length)
+                    .field("payload_length", payload_length)
                     .finish(),
+                &EventInner::BadProofOfWork(ref b) => b
+                    .as_ref()
+                    .into_iter()
+                    .fold(&mut f.debug_tuple("BadProofOfWork"), |d, b| d.field(b))
+                    .finish(),
+                &EventInner::NotTrusted(ref public_key) => {
+                    f.debug_tuple("NotTrusted").field(public_key).finish()
+                },
+                &EventInner::DeniedPeer(ref public_key) => {
+                    f.debug_tuple("DeniedPeer").field(public_key).finish()
+                },
+                &EventInner::QuotaExceeded(ref dropped) => {
+                    f.debug_tuple("QuotaExceeded").field(dropped).finish()
+                },
+                &EventInner::PortScan(ref ports) => f.debug_tuple("PortScan").field(ports).finish(),
             }
         }
     }
@@ -134,10 +737,46 @@ mod implementations {
                 EventInner::BlockedAlreadyConnected {
                     already_connected,
                     try_connect,
+                    public_key,
                 } => {
                     r[0..4].clone_from_slice(2u32.to_le_bytes().as_ref());
                     r[4..10].clone_from_slice(<[u8; 6]>::from(already_connected).as_ref());
                     r[10..16].clone_from_slice(<[u8; 6]>::from(try_connect).as_ref());
+                    r[16..48].clone_from_slice(public_key.as_ref());
+                    r
+                },
+                EventInner::NotTezosTraffic {
+                    chunk_length,
+                    payload_length,
+                } => {
+                    r[0..4].clone_from_slice(3u32.to_le_bytes().as_ref());
+                    r[4..6].clone_from_slice(chunk_length.to_le_bytes().as_ref());
+                    r[6..8].clone_from_slice(payload_length.to_le_bytes().as_ref());
+                    r
+                },
+                EventInner::BadProofOfWork(b) => {
+                    r[0..4].clone_from_slice(4u32.to_le_bytes().as_ref());
+                    r[4..].clone_from_slice(b.as_ref());
+                    r
+                },
+                EventInner::NotTrusted(public_key) => {
+                    r[0..4].clone_from_slice(5u32.to_le_bytes().as_ref());
+                    r[4..36].clone_from_slice(public_key.as_ref());
+                    r
+                },
+                EventInner::DeniedPeer(public_key) => {
+                    r[0..4].clone_from_slice(6u32.to_le_bytes().as_ref());
+                    r[4..36].clone_from_slice(public_key.as_ref());
+                    r
+                },
+                EventInner::QuotaExceeded(dropped) => {
+                    r[0..4].clone_from_slice(7u32.to_le_bytes().as_ref());
+                    r[4..8].clone_from_slice(dropped.to_le_bytes().as_ref());
+                    r
+                },
+                EventInner::PortScan(ports) => {
+                    r[0..4].clone_from_slice(8u32.to_le_bytes().as_ref());
+                    r[4..8].clone_from_slice(ports.to_le_bytes().as_ref());
                     r
                 },
             }
@@ -157,13 +796,296 @@ mod implementations {
                 2 => {
                     let already_connected = <[u8; 6]>::try_from(&r[4..10]).unwrap().into();
                     let try_connect = <[u8; 6]>::try_from(&r[10..16]).unwrap().into();
+                    let public_key = TryFrom::try_from(&r[16..48]).unwrap();
                     EventInner::BlockedAlreadyConnected {
                         already_connected,
                         try_connect,
+                        public_key,
                     }
                 },
+                3 => EventInner::NotTezosTraffic {
+                    chunk_length: u16::from_le_bytes(r[4..6].try_into().unwrap()),
+                    payload_length: u16::from_le_bytes(r[6..8].try_into().unwrap()),
+                },
+                4 => {
+                    let mut b = [0; 56];
+                    b.clone_from_slice(&r[4..]);
+                    EventInner::BadProofOfWork(b)
+                },
+                5 => EventInner::NotTrusted(TryFrom::try_from(&r[4..36]).unwrap()),
+                6 => EventInner::DeniedPeer(TryFrom::try_from(&r[4..36]).unwrap()),
+                7 => EventInner::QuotaExceeded(u32::from_le_bytes(r[4..8].try_into().unwrap())),
+                8 => EventInner::PortScan(u32::from_le_bytes(r[4..8].try_into().unwrap())),
                 _ => panic!(),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::convert::TryFrom;
+    use super::{
+        EventInner, TokenBucket, FlowQuota, PortScan, Config, ProtocolPolicy, Ipv4Layout, Tunnel, is_icmp_error,
+        is_udp_reply, ethernet_payload, tcp_payload, CONNECTION_MESSAGE_MIN_LENGTH, CONNECTION_MESSAGE_MAX_LENGTH,
+        IPPROTO_IPIP, IPPROTO_TCP, IPPROTO_UDP,
+    };
+
+    fn quota_config(byte_rate: u64, byte_burst: u64, packet_rate: u64, packet_burst: u64) -> Config {
+        Config {
+            pow_target: [0xff; 32],
+            private_mode: false,
+            syn_rate: 0,
+            syn_burst: 0,
+            syn_prefix_length: 32,
+            max_connections_per_ip: 0,
+            max_handshakes: 0,
+            flow_byte_rate: byte_rate,
+            flow_byte_burst: byte_burst,
+            flow_packet_rate: packet_rate,
+            flow_packet_burst: packet_burst,
+            quota_strikes: 0,
+            scan_ports: 0,
+            scan_window: 0,
+            udp_policy: ProtocolPolicy::Pass,
+            udp_rate: 0,
+            udp_burst: 0,
+            icmp_policy: ProtocolPolicy::Pass,
+            icmp_rate: 0,
+            icmp_burst: 0,
+            ban_all_protocols: false,
+        }
+    }
+
+    fn word(frame: &[u8]) -> impl Fn(usize) -> Option<[u8; 4]> + '_ {
+        move |offset| {
+            let bytes = frame.get(offset..(offset + 4))?;
+            Some([bytes[0], bytes[1], bytes[2], bytes[3]])
+        }
+    }
+
+    #[test]
+    fn ethernet() {
+        let mut frame = [0u8; 64];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(ethernet_payload(0, word(&frame)), Some((0x0800, 14)));
+
+        // 802.1Q
+        frame[12..14].copy_from_slice(&[0x81, 0x00]);
+        frame[16..18].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(ethernet_payload(0, word(&frame)), Some((0x0800, 18)));
+
+        // QinQ, the service tag, then the customer tag
+        frame[12..14].copy_from_slice(&[0x88, 0xa8]);
+        frame[16..18].copy_from_slice(&[0x81, 0x00]);
+        frame[20..22].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(ethernet_payload(0, word(&frame)), Some((0x0800, 22)));
+
+        // the third tag is not skipped
+        frame[20..22].copy_from_slice(&[0x81, 0x00]);
+        assert_eq!(ethernet_payload(0, word(&frame)), Some((0x8100, 22)));
+
+        // the frame of vxlan, the offset is relative to the packet
+        frame[30..32].copy_from_slice(&[0x86, 0xdd]);
+        assert_eq!(ethernet_payload(18, word(&frame)), Some((0x86dd, 32)));
+
+        // truncated
+        assert_eq!(ethernet_payload(0, word(&frame[..16])), None);
+        assert_eq!(ethernet_payload(0, word(&frame[..18])), None);
+    }
+
+    #[test]
+    fn ipv4_layout() {
+        let plain = Ipv4Layout::new(0x45, 0x4000).unwrap();
+        assert_eq!(
+            plain,
+            Ipv4Layout {
+                header_length: 20,
+                fragment: false,
+                headless: false,
+            }
+        );
+        // options
+        assert_eq!(Ipv4Layout::new(0x4f, 0).unwrap().header_length, 60);
+        // the first fragment
+        let first = Ipv4Layout::new(0x45, 0x2000).unwrap();
+        assert!(first.fragment && !first.headless);
+        // the last fragment
+        let last = Ipv4Layout::new(0x45, 185).unwrap();
+        assert!(last.fragment && last.headless);
+        // ipv6 and the short header
+        assert_eq!(Ipv4Layout::new(0x65, 0), None);
+        assert_eq!(Ipv4Layout::new(0x44, 0), None);
+    }
+
+    #[test]
+    fn payload_length() {
+        // 20 bytes of ipv4 and 32 bytes of tcp with options
+        assert_eq!(tcp_payload(14, 52 + 100, 34, 8), (66, 100));
+        // no payload, the padding of the short frame is not counted
+        assert_eq!(tcp_payload(14, 40, 34, 5), (54, 0));
+        // the broken data offset
+        assert_eq!(tcp_payload(14, 40, 34, 15).1, 0);
+        // inside the vxlan tunnel
+        assert_eq!(tcp_payload(14 + 36 + 14, 40 + 1, 14 + 36 + 14 + 20, 5), (104, 1));
+    }
+
+    #[test]
+    fn tunnel() {
+        let mut frame = [0u8; 64];
+        // the udp header behind the ipv4 header at 34
+        frame[36..38].copy_from_slice(&4789u16.to_be_bytes());
+        let member = || true;
+        let stranger = || false;
+
+        assert_eq!(Tunnel::find(IPPROTO_IPIP, false, 34, member, word(&frame)), Some(Tunnel::IpIp(34)));
+        assert_eq!(Tunnel::find(IPPROTO_UDP, false, 34, member, word(&frame)), Some(Tunnel::Vxlan(50)));
+        // the tunnels of the source which is not a configured endpoint stay unopened
+        assert_eq!(Tunnel::find(IPPROTO_IPIP, false, 34, stranger, word(&frame)), None);
+        assert_eq!(Tunnel::find(IPPROTO_UDP, false, 34, stranger, word(&frame)), None);
+        // the fragment, the other udp, tcp
+        assert_eq!(Tunnel::find(IPPROTO_IPIP, true, 34, member, word(&frame)), None);
+        frame[36..38].copy_from_slice(&53u16.to_be_bytes());
+        assert_eq!(Tunnel::find(IPPROTO_UDP, false, 34, member, word(&frame)), None);
+        assert_eq!(Tunnel::find(IPPROTO_TCP, false, 34, member, word(&frame)), None);
+        // the endpoint is looked up only for the tunnel
+        let lookup = || panic!("looked up");
+        assert_eq!(Tunnel::find(IPPROTO_TCP, false, 34, lookup, word(&frame)), None);
+    }
+
+    #[test]
+    fn protocol_policy() {
+        assert_eq!("pass".parse(), Ok(ProtocolPolicy::Pass));
+        assert_eq!("drop".parse(), Ok(ProtocolPolicy::Drop));
+        assert_eq!("rate-limit".parse(), Ok(ProtocolPolicy::RateLimit));
+        assert!("ratelimit".parse::<ProtocolPolicy>().is_err());
+        assert!("".parse::<ProtocolPolicy>().is_err());
+
+        // echo request and echo reply
+        assert!(!is_icmp_error(8));
+        assert!(!is_icmp_error(0));
+        assert!(is_icmp_error(3));
+        assert!(is_icmp_error(11));
+        assert!(is_icmp_error(12));
+        assert!(is_udp_reply(53));
+        assert!(is_udp_reply(123));
+        assert!(!is_udp_reply(4789));
+    }
+
+    #[test]
+    fn port_scan() {
+        let second = TokenBucket::TOKEN;
+        let mut scan = PortScan::new(0);
+        assert_eq!(scan.hit(22, 0, 10 * second), 1);
+        assert_eq!(scan.hit(22, second, 10 * second), 1);
+        // the same low byte
+        assert_eq!(scan.hit(22 + 0x100, second, 10 * second), 2);
+        assert_eq!(scan.hit(22 + 0x200, second, 10 * second), 3);
+        // a sequential scan
+        let count = (1000..1100).fold(0, |_, port| scan.hit(port, 2 * second, 10 * second));
+        assert_eq!(count, 102);
+        // the window is over
+        assert_eq!(scan.hit(22, 11 * second, 10 * second), 1);
+        assert_eq!(scan.since, 11 * second);
+    }
+
+    #[test]
+    fn take_many() {
+        let second = TokenBucket::TOKEN;
+        // the default byte burst and a slow byte rate
+        let burst = 4 << 20;
+        let mut bucket = TokenBucket { credit: 0, last: 0 };
+        assert!(!bucket.take_many(0, 1000, burst, 1500));
+        // the bucket is full after an hour and a bit
+        assert!(bucket.take_many(5000 * second, 1000, burst, burst));
+        assert!(!bucket.take_many(5000 * second, 1000, burst, 1));
+        assert!(bucket.take_many(5000 * second + second, 1000, burst, 1000));
+    }
+
+    #[test]
+    fn flow_quota() {
+        let second = TokenBucket::TOKEN;
+        let config = quota_config(1000, 3000, 10, 2);
+        let mut quota = FlowQuota::new(0, &config);
+        assert!(quota.spend(0, 1500, &config));
+        // over the byte quota, the packet quota is not spent
+        assert!(!quota.spend(0, 1600, &config));
+        assert_eq!(quota.dropped, 1);
+        assert!(quota.spend(0, 1500, &config));
+        // over the packet quota
+        assert!(!quota.spend(0, 1, &config));
+        assert_eq!(quota.dropped, 2);
+        assert!(quota.spend(second, 1000, &config));
+
+        // only the packets are limited
+        let config = quota_config(0, 0, 1, 1);
+        let mut quota = FlowQuota::new(0, &config);
+        assert!(quota.spend(0, 60000, &config));
+        assert!(!quota.spend(0, 1, &config));
+        assert!(quota.spend(second, 60000, &config));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let second = TokenBucket::TOKEN;
+        let mut bucket = TokenBucket { credit: 0, last: 0 };
+        assert!(!bucket.take(0, 1, 100));
+        // slow rate refills the bucket up to the burst
+        assert!(bucket.take_many(100 * second, 1, 100, 100));
+        assert!(!bucket.take(100 * second, 1, 100));
+        // but not over it
+        assert!(bucket.take_many(1000 * second, 1, 100, 100));
+        assert!(!bucket.take(1000 * second, 1, 100));
+        // the clock might go back between cpus
+        assert!(!bucket.take(999 * second, 1, 100));
+        assert!(bucket.take(1001 * second, 1, 100));
+        // zero rate is never refilled
+        let mut bucket = TokenBucket { credit: second, last: 0 };
+        assert!(bucket.take(0, 0, 1));
+        assert!(!bucket.take(1000 * second, 0, 1));
+    }
+
+    #[test]
+    fn connection_message_length() {
+        // port, public key, proof of work stamp, nonce, empty chain name, two versions
+        let message = [0u8; 2 + 32 + 24 + 24 + 4 + 2 + 2];
+        assert_eq!(u16::try_from(message.len()).unwrap(), CONNECTION_MESSAGE_MIN_LENGTH);
+    }
+
+    #[test]
+    fn chunk_framing() {
+        // the whole chunk in the packet
+        assert!(EventInner::check_chunk_framing(100, 102).is_none());
+        // the first segment of the chunk split among several packets
+        assert!(EventInner::check_chunk_framing(100, 60).is_none());
+        // too short to read the proof of work
+        assert!(matches!(
+            EventInner::check_chunk_framing(100, 59),
+            Some(EventInner::NotEnoughBytesForPow)
+        ));
+        assert!(EventInner::check_chunk_framing(CONNECTION_MESSAGE_MAX_LENGTH, 536).is_none());
+        // followed by something
+        assert!(EventInner::check_chunk_framing(100, 103).is_some());
+        // too short or too long for `ConnectionMessage`
+        assert!(EventInner::check_chunk_framing(CONNECTION_MESSAGE_MIN_LENGTH - 1, 20).is_some());
+        assert!(EventInner::check_chunk_framing(CONNECTION_MESSAGE_MAX_LENGTH + 1, 1448).is_some());
+        // `GET / HTTP/1.1`
+        let http = u16::from_be_bytes([b'G', b'E']);
+        assert!(EventInner::check_chunk_framing(http, 78).is_some());
+    }
+
+    #[test]
+    fn not_tezos_traffic_roundtrip() {
+        let event = EventInner::check_chunk_framing(0x4745, 78).unwrap();
+        match EventInner::from(<[u8; 60]>::from(event)) {
+            EventInner::NotTezosTraffic {
+                chunk_length,
+                payload_length,
+            } => {
+                assert_eq!(chunk_length, 0x4745);
+                assert_eq!(payload_length, 78);
+            },
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// maps which `redbpf_probes` does not provide, the loader creates a map
+// of any type described by `bpf_map_def` in the `maps/` section
+
+use core::{marker::PhantomData, mem};
+use redbpf_probes::xdp::prelude::*;
+
+/// Hash map which evicts the least recently used entry instead of failing to insert a new one
+#[repr(transparent)]
+pub struct LruHashMap<K, V> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> LruHashMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        LruHashMap {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LRU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut V))
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, key: &K, value: &V) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+
+    #[inline(always)]
+    pub fn delete(&mut self, key: &K) {
+        unsafe {
+            bpf_map_delete_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+        }
+    }
+}
+
+/// Longest prefix match trie, the key must start with the prefix length in bits, see `Ipv4Prefix`
+#[repr(transparent)]
+pub struct LpmTrieMap<K, V> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> LpmTrieMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        LpmTrieMap {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LPM_TRIE,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                // the kernel refuses to preallocate the trie
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// the value of the longest prefix which contains the key
+    #[inline(always)]
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const V))
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Blake2b-256 of a single block, enough for public key and proof of work stamp,
+// everything is unrolled with constant indexes, so the verifier can accept it
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+macro_rules! g {
+    ($v:ident, $a:expr, $b:expr, $c:expr, $d:expr, $x:expr, $y:expr) => {
+        $v[$a] = $v[$a].wrapping_add($v[$b]).wrapping_add($x);
+        $v[$d] = ($v[$d] ^ $v[$a]).rotate_right(32);
+        $v[$c] = $v[$c].wrapping_add($v[$d]);
+        $v[$b] = ($v[$b] ^ $v[$c]).rotate_right(24);
+        $v[$a] = $v[$a].wrapping_add($v[$b]).wrapping_add($y);
+        $v[$d] = ($v[$d] ^ $v[$a]).rotate_right(16);
+        $v[$c] = $v[$c].wrapping_add($v[$d]);
+        $v[$b] = ($v[$b] ^ $v[$c]).rotate_right(63);
+    };
+}
+
+macro_rules! round {
+    ($v:ident, $m:ident, [$s0:expr, $s1:expr, $s2:expr, $s3:expr, $s4:expr, $s5:expr, $s6:expr, $s7:expr,
+        $s8:expr, $s9:expr, $s10:expr, $s11:expr, $s12:expr, $s13:expr, $s14:expr, $s15:expr]) => {
+        g!($v, 0, 4, 8, 12, $m[$s0], $m[$s1]);
+        g!($v, 1, 5, 9, 13, $m[$s2], $m[$s3]);
+        g!($v, 2, 6, 10, 14, $m[$s4], $m[$s5]);
+        g!($v, 3, 7, 11, 15, $m[$s6], $m[$s7]);
+        g!($v, 0, 5, 10, 15, $m[$s8], $m[$s9]);
+        g!($v, 1, 6, 11, 12, $m[$s10], $m[$s11]);
+        g!($v, 2, 7, 8, 13, $m[$s12], $m[$s13]);
+        g!($v, 3, 4, 9, 14, $m[$s14], $m[$s15]);
+    };
+}
+
+/// public key and proof of work stamp
+pub const POW_DATA_LENGTH: usize = 56;
+
+#[inline(always)]
+pub fn blake2b_256(data: &[u8; POW_DATA_LENGTH]) -> [u8; 32] {
+    let mut m = [0u64; 16];
+    for i in 0..(POW_DATA_LENGTH / 8) {
+        let mut word = [0; 8];
+        word.clone_from_slice(&data[(i * 8)..(i * 8 + 8)]);
+        m[i] = u64::from_le_bytes(word);
+    }
+
+    // parameter block: 32 bytes digest, no key, fanout 1, depth 1
+    let mut h = IV;
+    h[0] ^= 0x01010000 ^ 32;
+
+    let mut v = [0u64; 16];
+    v[..8].clone_from_slice(&h);
+    v[8..].clone_from_slice(&IV);
+    // the only block is also the last one
+    v[12] ^= POW_DATA_LENGTH as u64;
+    v[14] = !v[14];
+
+    round!(v, m, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    round!(v, m, [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3]);
+    round!(v, m, [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4]);
+    round!(v, m, [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8]);
+    round!(v, m, [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13]);
+    round!(v, m, [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9]);
+    round!(v, m, [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11]);
+    round!(v, m, [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10]);
+    round!(v, m, [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5]);
+    round!(v, m, [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0]);
+    round!(v, m, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    round!(v, m, [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3]);
+
+    let mut r = [0; 32];
+    for i in 0..4 {
+        h[i] ^= v[i] ^ v[i + 8];
+        r[(i * 8)..(i * 8 + 8)].clone_from_slice(h[i].to_le_bytes().as_ref());
+    }
+    r
+}
+
+/// the same as `check_proof_of_work` in tezos `crypto_box.ml`,
+/// the hash is a little endian number, it should not exceed the target,
+/// the target is a big endian number, userspace computes it from the float complexity
+#[inline(always)]
+pub fn check_proof_of_work(data: &[u8; POW_DATA_LENGTH], target: &[u8; 32]) -> bool {
+    let hash = blake2b_256(data);
+    for i in 0..32 {
+        let h = hash[31 - i];
+        if h != target[i] {
+            return h < target[i];
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blake2b_256, check_proof_of_work};
+
+    #[test]
+    fn blake2b() {
+        let mut data = [0; 56];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let expected = [
+            0xa6, 0xc1, 0xd3, 0x5f, 0x74, 0x21, 0x8e, 0x57, 0xb4, 0xdb, 0xd4, 0xed, 0x46, 0x7b,
+            0x41, 0x98, 0x12, 0x08, 0x66, 0x67, 0x38, 0xff, 0xa0, 0xd9, 0xdc, 0x53, 0xd3, 0xde,
+            0x96, 0xbe, 0x70, 0x2a,
+        ];
+        assert_eq!(blake2b_256(&data), expected);
+    }
+
+    #[test]
+    fn target() {
+        let data = [0; 56];
+        assert!(check_proof_of_work(&data, &[0xff; 32]));
+        assert!(!check_proof_of_work(&data, &[0; 32]));
+    }
+}